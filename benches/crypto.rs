@@ -0,0 +1,151 @@
+// Benchmarks of the AEAD stream framing over an in-memory pipe.
+//
+// To compare a change, save a baseline on the tree before it and compare the tree
+// after against it:
+//
+//   cargo bench --bench crypto -- --save-baseline before
+//   cargo bench --bench crypto -- --baseline before
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+use socks5::crypto::{evp_bytes_to_key, CipherKind, CryptoReader, CryptoWriter};
+
+const ADDR: [u8; 7] = [0x01, 0x7f, 0x00, 0x00, 0x01, 0x00, 0x50];
+const BULK_LEN: usize = 4 << 20;
+const INTERACTIVE_WRITES: usize = 1024;
+const INTERACTIVE_WRITE_LEN: usize = 64;
+// interactive traffic flushes after a burst of this many writes.
+const INTERACTIVE_BURST: usize = 8;
+
+fn key(kind: CipherKind) -> Vec<u8> {
+    let key = evp_bytes_to_key("foobar".to_string(), kind.key_len());
+    if kind.is_2022() {
+        return kind.derive_key(&base64::encode(key)).unwrap();
+    }
+    key
+}
+
+// relay writes data through a CryptoWriter and reads it back through a CryptoReader.
+async fn relay(kind: CipherKind, key: &[u8], data: &[u8], coalesce: bool, burst: usize) -> usize {
+    let (client, server) = io::duplex(1 << 16);
+    let mut writer = CryptoWriter::new(client, kind, key).with_coalesce(coalesce);
+    let mut reader = CryptoReader::new(server, kind, key);
+
+    let write = async {
+        writer.write_all(&ADDR).await.unwrap();
+        for (i, chunk) in data.chunks(burst).enumerate() {
+            writer.write_all(chunk).await.unwrap();
+            if (i + 1) % INTERACTIVE_BURST == 0 {
+                writer.flush().await.unwrap();
+            }
+        }
+        writer.shutdown().await.unwrap();
+    };
+    let read = async {
+        let mut buf = Vec::with_capacity(ADDR.len() + data.len());
+        reader.read_to_end(&mut buf).await.unwrap()
+    };
+    let (_, n) = futures::join!(write, read);
+    n
+}
+
+fn bench_bulk_copy(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let data = vec![0x5au8; BULK_LEN];
+
+    let mut group = c.benchmark_group("bulk_copy");
+    group.throughput(Throughput::Bytes(BULK_LEN as u64));
+    group.sample_size(20);
+    for kind in [
+        CipherKind::Aes128Gcm,
+        CipherKind::Aes256Gcm,
+        CipherKind::Chacha20IetfPoly1305,
+        CipherKind::Blake3Aes256Gcm,
+    ] {
+        let key = key(kind);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", kind)),
+            &kind,
+            |b, &kind| {
+                // writes as large as io::copy does.
+                b.iter(|| rt.block_on(relay(kind, &key, &data, false, 8192)))
+            },
+        );
+    }
+    group.finish();
+}
+
+// loopback_relay copies data from a client to a server over a loopback TCP connection,
+// as the client and server relay a proxied connection.
+async fn loopback_relay(kind: CipherKind, key: &[u8], data: &[u8]) -> u64 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = async {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut writer = CryptoWriter::new(stream, kind, key);
+        writer.write_all(&ADDR).await.unwrap();
+        io::copy(&mut &data[..], &mut writer).await.unwrap();
+        writer.shutdown().await.unwrap();
+    };
+    let server = async {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = CryptoReader::new(stream, kind, key);
+        io::copy(&mut reader, &mut io::sink()).await.unwrap()
+    };
+    let (_, n) = futures::join!(client, server);
+    n
+}
+
+fn bench_loopback_relay(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let data = vec![0x5au8; BULK_LEN];
+
+    let mut group = c.benchmark_group("loopback_relay");
+    group.throughput(Throughput::Bytes(BULK_LEN as u64));
+    group.sample_size(20);
+    for kind in [
+        CipherKind::Aes128Gcm,
+        CipherKind::Chacha20IetfPoly1305,
+        CipherKind::Blake3Aes128Gcm,
+    ] {
+        let key = key(kind);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", kind)),
+            &kind,
+            |b, &kind| b.iter(|| rt.block_on(loopback_relay(kind, &key, &data))),
+        );
+    }
+    group.finish();
+}
+
+fn bench_interactive(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let data = vec![0x5au8; INTERACTIVE_WRITES * INTERACTIVE_WRITE_LEN];
+    let kind = CipherKind::Aes128Gcm;
+    let key = key(kind);
+
+    let mut group = c.benchmark_group("interactive");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for coalesce in [false, true] {
+        let name = if coalesce { "coalesce" } else { "no_coalesce" };
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name),
+            &coalesce,
+            |b, &coalesce| {
+                b.iter(|| rt.block_on(relay(kind, &key, &data, coalesce, INTERACTIVE_WRITE_LEN)))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_bulk_copy,
+    bench_loopback_relay,
+    bench_interactive
+);
+criterion_main!(benches);
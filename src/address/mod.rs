@@ -7,7 +7,7 @@ use url::{Host, Url};
 use log::debug;
 use tokio::io;
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use tokio::net::{lookup_host, TcpStream};
 
 pub const IPV4_ADDR: u8 = 0x1;
 pub const DOMAIN_ADDR: u8 = 0x3;
@@ -44,12 +44,39 @@ impl Address {
         }
     }
 
+    // host returns the domain or the IP of address, without the port.
+    pub fn host(&self) -> String {
+        match self {
+            Address::SocketAddr(addr) => addr.ip().to_string(),
+            Address::DomainAddr(host, _) => host.clone(),
+        }
+    }
+
     pub async fn new_conn(self) -> io::Result<TcpStream> {
         return match self {
             Address::SocketAddr(_addr) => TcpStream::connect(_addr).await,
-            Address::DomainAddr(ref _host, _port) => TcpStream::connect((&_host[..], _port)).await,
+            Address::DomainAddr(ref _host, _port) => {
+                let addrs: Vec<SocketAddr> = lookup_host((&_host[..], _port))
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::HostUnreachable, e))?
+                    .collect();
+                TcpStream::connect(&addrs[..]).await
+            }
         };
     }
+
+    // resolve looks up the first socket address of a domain address.
+    pub async fn resolve(&self) -> io::Result<SocketAddr> {
+        match self {
+            Address::SocketAddr(addr) => Ok(*addr),
+            Address::DomainAddr(host, port) => {
+                match lookup_host((&host[..], *port)).await?.next() {
+                    Some(addr) => Ok(addr),
+                    None => Err(io::Error::other(format!("can't resolve {}", host))),
+                }
+            }
+        }
+    }
 }
 
 impl Display for Address {
@@ -70,9 +97,9 @@ impl Display for Address {
 impl ToSocketAddrs for Address {
     type Iter = vec::IntoIter<SocketAddr>;
     fn to_socket_addrs(&self) -> io::Result<vec::IntoIter<SocketAddr>> {
-        match self.clone() {
-            &Address::SocketAddr(addr) => Ok(vec![addr].into_iter()),
-            &Address::DomainAddr(ref host, port) => (&host[..], port).to_socket_addrs(),
+        match self {
+            Address::SocketAddr(addr) => Ok(vec![*addr].into_iter()),
+            Address::DomainAddr(host, port) => (&host[..], *port).to_socket_addrs(),
         }
     }
 }
@@ -99,25 +126,25 @@ impl ToSocketAddrs for Address {
 //			o  X’04’
 //    the address is a version-6 IP address, with a length of 16 octets.
 pub async fn get_address<T: Unpin + AsyncReadExt>(r: &mut T) -> io::Result<Address> {
-    let atyp = r.read_u8().await.unwrap();
+    let atyp = r.read_u8().await?;
 
     let raw_addr_len = match atyp {
         IPV4_ADDR => IPV4_LEN,
-        DOMAIN_ADDR => r.read_u8().await.unwrap() as usize,
+        DOMAIN_ADDR => r.read_u8().await? as usize,
         IPV6_ADDR => IPV6_LEN,
         _ => {
             debug!("unsupported address type");
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::Unsupported,
                 "unsupported address type",
             ));
         }
     };
 
     let mut raw_addr = [0u8; 260];
-    let _ = r.read_exact(&mut raw_addr[0..raw_addr_len]).await;
+    r.read_exact(&mut raw_addr[0..raw_addr_len]).await?;
 
-    let port = r.read_u16().await.unwrap();
+    let port = r.read_u16().await?;
 
     match atyp {
         IPV4_ADDR => {
@@ -174,7 +201,7 @@ pub fn get_address_from_url(host: String, port: u16) -> io::Result<Address> {
 }
 
 pub async fn get_raw_address<T: Unpin + AsyncReadExt>(r: &mut T) -> io::Result<Vec<u8>> {
-    let atyp = r.read_u8().await.unwrap();
+    let atyp = r.read_u8().await?;
 
     let mut raw_addr = [0u8; 260];
     raw_addr[0] = atyp;
@@ -182,7 +209,7 @@ pub async fn get_raw_address<T: Unpin + AsyncReadExt>(r: &mut T) -> io::Result<V
     let raw_addr_len = match atyp {
         IPV4_ADDR => IPV4_LEN,
         DOMAIN_ADDR => {
-            let len = r.read_u8().await.unwrap();
+            let len = r.read_u8().await?;
             raw_addr[1] = len;
             i = 2;
             len as usize
@@ -190,13 +217,13 @@ pub async fn get_raw_address<T: Unpin + AsyncReadExt>(r: &mut T) -> io::Result<V
         IPV6_ADDR => IPV6_LEN,
         _ => {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::Unsupported,
                 "unsupported address type",
             ));
         }
     };
 
-    let _ = r.read_exact(&mut raw_addr[i..raw_addr_len + i + 2]).await;
+    r.read_exact(&mut raw_addr[i..raw_addr_len + i + 2]).await?;
     let a = &raw_addr[..raw_addr_len + i + 2];
     Ok(Vec::from(a))
 }
@@ -243,9 +270,61 @@ pub fn parse_address_from_vec(ary: &[u8]) -> io::Result<Address> {
         _ => {
             debug!("unsupported address type");
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::Unsupported,
                 "unsupported address type",
             ));
         }
     }
 }
+
+// raw_address_len returns the length of the ATYP | DST.ADDR | DST.PORT prefix of ary.
+pub fn raw_address_len(ary: &[u8]) -> io::Result<usize> {
+    let len = match ary.first() {
+        Some(&IPV4_ADDR) => 1 + IPV4_LEN + 2,
+        Some(&DOMAIN_ADDR) if ary.len() > 1 => 2 + ary[1] as usize + 2,
+        Some(&IPV6_ADDR) => 1 + IPV6_LEN + 2,
+        Some(&DOMAIN_ADDR) | None => 0,
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unsupported address type",
+            ))
+        }
+    };
+    if len == 0 || len > ary.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(len)
+}
+
+// address_to_vec encodes addr as ATYP | ADDR | PORT.
+pub fn address_to_vec(addr: &Address) -> Vec<u8> {
+    match addr {
+        Address::SocketAddr(addr) => socket_addr_to_vec(addr),
+        Address::DomainAddr(host, port) => {
+            let mut raw = Vec::with_capacity(2 + host.len() + 2);
+            raw.push(DOMAIN_ADDR);
+            raw.push(host.len() as u8);
+            raw.extend_from_slice(host.as_bytes());
+            raw.extend_from_slice(&port.to_be_bytes());
+            raw
+        }
+    }
+}
+
+// socket_addr_to_vec encodes addr as ATYP | ADDR | PORT.
+pub fn socket_addr_to_vec(addr: &SocketAddr) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(1 + IPV6_LEN + 2);
+    match addr {
+        SocketAddr::V4(addr) => {
+            raw.push(IPV4_ADDR);
+            raw.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            raw.push(IPV6_ADDR);
+            raw.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    raw.extend_from_slice(&addr.port().to_be_bytes());
+    raw
+}
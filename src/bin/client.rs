@@ -3,24 +3,15 @@ use std::sync::Arc;
 use clap::{App, Arg};
 use log::{error, info};
 use pretty_env_logger;
-use tokio::io;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 
 use socks5::config;
 use socks5::crypto;
+use socks5::local;
 use socks5::manager::HTTPManager;
 use socks5::socks::acl;
 use socks5::socks::server;
-use socks5::socks::TCPRelay;
-
-async fn handle(
-    stream: TcpStream,
-    server_manager: Arc<server::ServerManager>,
-    acl_manager: Arc<acl::ACLManager>,
-) -> io::Result<()> {
-    let socks5s = TCPRelay::new(acl_manager, server_manager);
-    socks5s.serve(stream).await
-}
+use socks5::transport::plugin;
 
 #[tokio::main(worker_threads = 10)]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -43,16 +34,45 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut cfg = config::parse_conf(config_path.to_string())?;
 
     for srv in cfg.server.iter_mut() {
-        let key = crypto::evp_bytes_to_key(srv.password.clone(), 16);
-        srv.key = key
+        srv.cipher = srv.method.parse()?;
+        srv.key = srv.cipher.derive_key(&srv.password)?;
     }
 
-    let acl_manager = Arc::new(acl::ACLManager::new(cfg.acl_cfg));
-    let server_manager = Arc::new(server::ServerManager::new(cfg.server, cfg.proxy_group));
+    let replay_filter = Arc::new(crypto::ReplayFilter::new(
+        cfg.replay_filter.capacity,
+        cfg.replay_filter.fp_rate,
+    )?);
+    let server_manager = Arc::new(server::ServerManager::new(
+        cfg.server,
+        cfg.proxy_group,
+        replay_filter,
+    )?);
+    server_manager.start_url_tests()?;
 
-    let local = format!("{}:{}", cfg.local[0].address, cfg.local[0].port);
-    let listen = TcpListener::bind(&local).await?;
-    info!("Server listens at {}.", local);
+    let global_acl_cfg = cfg.acl_cfg;
+    let mut listeners = Vec::with_capacity(cfg.local.len());
+    for mut local_cfg in cfg.local {
+        let acl_cfg = local::acl_config(&mut local_cfg, &global_acl_cfg);
+        let acl_manager = Arc::new(acl::ACLManager::new(acl_cfg));
+
+        let listen = TcpListener::bind((local_cfg.address.as_str(), local_cfg.port)).await?;
+        info!(
+            "Server listens at {} for {:?}.",
+            listen.local_addr()?,
+            local_cfg.protocol
+        );
+        listeners.push((listen, Arc::new(local_cfg), acl_manager));
+    }
+
+    if cfg.health_check.interval > 0 {
+        let sm = server_manager.clone();
+        let health_check = cfg.health_check;
+        tokio::spawn(async move {
+            if let Err(e) = sm.check_health(health_check).await {
+                error!("health check stopped {}", e);
+            }
+        });
+    }
 
     let sm = server_manager.clone();
     tokio::spawn(async move {
@@ -60,16 +80,18 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
         mgr.start().await;
     });
 
-    loop {
-        let (stream, _) = match listen.accept().await {
-            Ok(a) => a,
-            Err(err) => {
-                error!("{}", err);
-                return Ok(());
-            }
-        };
-        let sk = server_manager.clone();
-        let acl_manager = acl_manager.clone();
-        tokio::spawn(async move { handle(stream, sk, acl_manager).await });
+    let mut tasks = Vec::with_capacity(listeners.len());
+    for (listen, local_cfg, acl_manager) in listeners {
+        tasks.push(tokio::spawn(local::serve(
+            listen,
+            server_manager.clone(),
+            acl_manager,
+            local_cfg,
+        )));
+    }
+    tokio::select! {
+        _ = futures::future::join_all(tasks) => {}
+        _ = plugin::shutdown() => {}
     }
+    Ok(())
 }
@@ -1,19 +1,33 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{App, Arg};
-use tokio::net::{TcpListener, TcpStream};
+use log::error;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 use socks5::config;
 use socks5::crypto;
+use socks5::mika::udp::UDPRelay;
 use socks5::mika::TCPRelay;
+use socks5::transport::plugin::{self, Plugin};
+use socks5::transport::tls;
 
-async fn handle(stream: TcpStream, secret_key: &Vec<u8>) {
-    let mika = TCPRelay::new();
-    mika.serve(stream, secret_key).await;
+// UDP sessions expire after this many seconds unless the server sets a timeout.
+const UDP_IDLE_TIMEOUT: u64 = 300;
+
+async fn handle(
+    stream: TcpStream,
+    mika: TCPRelay,
+    cipher: crypto::CipherKind,
+    secret_key: &Vec<u8>,
+) {
+    mika.serve(stream, cipher, secret_key).await;
 }
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init_timed();
+
     let matches = App::new("Mika server")
         .version("1.0")
         .author("Sake")
@@ -30,18 +44,68 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config_path = matches.value_of("config").unwrap_or("mika.cfg");
     let cfg = config::parse_conf(config_path.to_string())?;
 
-    let key = crypto::evp_bytes_to_key(cfg.server[0].password.clone(), 16);
+    let cipher = cfg.server[0].method.parse::<crypto::CipherKind>()?;
+    let key = cipher.derive_key(&cfg.server[0].password)?;
     let secret_key = Arc::new(key);
+    let replay_filter = Arc::new(crypto::ReplayFilter::new(
+        cfg.replay_filter.capacity,
+        cfg.replay_filter.fp_rate,
+    )?);
+    let mut mika = TCPRelay::new(replay_filter, cfg.server[0].coalesce);
+    if !cfg.server[0].obfs_url.is_empty() {
+        mika = mika.with_obfs(cfg.server[0].obfs_url.clone(), cfg.server[0].obfs);
+    }
+    if let Some(ws) = &cfg.server[0].ws {
+        mika = mika.with_websocket(ws.clone());
+    }
+    if let Some(tls_cfg) = &cfg.server[0].tls {
+        mika = mika.with_tls(tls::acceptor(tls_cfg)?);
+    }
 
     let local = format!("0.0.0.0:{}", cfg.server[0].port);
-    let listen = TcpListener::bind(&local).await?;
+    // with a plugin listening on port, the stream is served on a loopback port the
+    // plugin connects to. It's bound before the plugin starts so that no other process
+    // can take it. UDP isn't carried by plugins and stays on port.
+    let listen = if cfg.server[0].plugin.is_empty() {
+        TcpListener::bind(&local).await?
+    } else {
+        let listen = TcpListener::bind("127.0.0.1:0").await?;
+        Plugin::with_local(
+            &cfg.server[0].plugin,
+            &cfg.server[0].plugin_opts,
+            "0.0.0.0",
+            cfg.server[0].port as u16,
+            listen.local_addr()?,
+        )
+        .start()?;
+        listen
+    };
     println!("Server listens at {}.", local);
 
-    loop {
-        let (stream, _) = listen.accept().await?;
-        let sk = secret_key.clone();
-        tokio::spawn(async move {
-            handle(stream, &sk).await;
-        });
+    let udp = UdpSocket::bind(&local).await?;
+    let idle_timeout = match cfg.server[0].timeout {
+        t if t > 0 => Duration::from_secs(t as u64),
+        _ => Duration::from_secs(UDP_IDLE_TIMEOUT),
+    };
+    let sk = secret_key.clone();
+    tokio::spawn(async move {
+        if let Err(e) = UDPRelay::new(idle_timeout).serve(udp, cipher, &sk).await {
+            error!("udp relay stopped {}", e);
+        }
+    });
+
+    let accept = async {
+        loop {
+            let (stream, _) = listen.accept().await?;
+            let sk = secret_key.clone();
+            let mika = mika.clone();
+            tokio::spawn(async move {
+                handle(stream, mika, cipher, &sk).await;
+            });
+        }
+    };
+    tokio::select! {
+        r = accept => r,
+        _ = plugin::shutdown() => Ok(()),
     }
 }
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::Result;
 use std::io::{Error, ErrorKind};
@@ -5,6 +6,8 @@ use std::io::{Error, ErrorKind};
 use serde::{Deserialize, Serialize};
 use serde_yaml;
 
+use crate::crypto::CipherKind;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Server {
     #[serde(default)]
@@ -15,16 +18,141 @@ pub struct Server {
     pub timeout: i32,
     #[serde(default)]
     pub obfs_url: String,
+    // obfs is the mode of obfs, which is enabled by obfs_url.
+    #[serde(default)]
+    pub obfs: ObfsMode,
+    // plugin is the path of a SIP003 plugin the connection goes through.
+    #[serde(default)]
+    pub plugin: String,
+    #[serde(default)]
+    pub plugin_opts: String,
     pub password: String,
     #[serde(skip)]
     pub key: Vec<u8>,
     pub method: String,
+    #[serde(skip)]
+    pub cipher: CipherKind,
+    // coalesce buffers small writes into one chunk until flush.
+    #[serde(default)]
+    pub coalesce: bool,
+    // ws carries the stream in websocket frames, e.g. behind a reverse proxy.
+    #[serde(default)]
+    pub ws: Option<WebSocketConfig>,
+    // tls wraps the connection in TLS, under ws if both are set.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Local {
     pub address: String,
-    pub port: i32,
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+    // users allowed to connect, empty means no authentication.
+    #[serde(default)]
+    pub users: Vec<User>,
+    // acl replaces the global acl for this listener.
+    #[serde(default)]
+    pub acl: Option<ACLConfig>,
+    // fnl replaces the final policy of the acl for this listener.
+    #[serde(default, rename = "final")]
+    pub fnl: Option<Policy>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Socks5,
+    Http,
+    // Mixed detects socks4/4a, socks5 and http on one listener.
+    Mixed,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObfsMode {
+    // Http sends a websocket upgrade request first.
+    #[default]
+    Http,
+    // Tls mimics a TLS 1.2 session.
+    Tls,
+}
+
+// WebSocketConfig configures the websocket transport.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebSocketConfig {
+    #[serde(default = "default_ws_path")]
+    pub path: String,
+    // host is the Host header, the server address by default. The server checks
+    // it when set.
+    #[serde(default)]
+    pub host: String,
+    // headers are sent along with the handshake of client.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    // ping_interval is the seconds between pings, 0 disables them.
+    #[serde(default)]
+    pub ping_interval: u64,
+}
+
+fn default_ws_path() -> String {
+    "/".to_string()
+}
+
+// TlsConfig configures the TLS transport. Clients use server_name, alpn, ca and
+// pins, the server listens with cert and key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    // server_name is sent as SNI and verified, the server address by default.
+    #[serde(default)]
+    pub server_name: String,
+    // alpn are the protocols offered by client and accepted by server.
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    // ca is the path of a PEM bundle trusted instead of the webpki roots.
+    #[serde(default)]
+    pub ca: String,
+    // pins are hex SHA-256 digests of certificates accepted in place of a chain
+    // verified, e.g. a self-signed one.
+    #[serde(default)]
+    pub pins: Vec<String>,
+    // cert and key are the paths of the PEM certificate chain and private key.
+    #[serde(default)]
+    pub cert: String,
+    #[serde(default)]
+    pub key: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            path: default_ws_path(),
+            host: String::new(),
+            headers: BTreeMap::new(),
+            ping_interval: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub password: String,
+}
+
+impl User {
+    // verify checks the credentials of a client, the password is compared in constant
+    // time so that its matching prefix doesn't leak through timing.
+    pub fn verify(&self, username: &str, password: &[u8]) -> bool {
+        let expected = self.password.as_bytes();
+        let mut diff = (expected.len() != password.len()) as u8;
+        for (i, b) in expected.iter().enumerate() {
+            diff |= b ^ password.get(i).copied().unwrap_or_default();
+        }
+        self.username == username && diff == 0
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -35,6 +163,81 @@ pub struct Config {
     pub local: Vec<Local>,
     #[serde(rename = "acl")]
     pub acl_cfg: ACLConfig,
+    #[serde(default)]
+    pub replay_filter: ReplayFilterConfig,
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+}
+
+// ReplayFilterConfig sizes the filter of recently seen salts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReplayFilterConfig {
+    // capacity is the number of salts remembered at least.
+    #[serde(default = "default_replay_capacity")]
+    pub capacity: usize,
+    #[serde(default = "default_replay_fp_rate")]
+    pub fp_rate: f64,
+}
+
+fn default_replay_capacity() -> usize {
+    1_000_000
+}
+
+fn default_replay_fp_rate() -> f64 {
+    1e-6
+}
+
+impl Default for ReplayFilterConfig {
+    fn default() -> Self {
+        ReplayFilterConfig {
+            capacity: default_replay_capacity(),
+            fp_rate: default_replay_fp_rate(),
+        }
+    }
+}
+
+// HealthCheckConfig configures the probes made through each server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    // interval is the seconds between checks, 0 disables them.
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    // timeout is the seconds a probe may take.
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+    // url is the http target requested through servers.
+    #[serde(default = "default_health_url")]
+    pub url: String,
+    // max_fails is the failure streak marking a server down.
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+}
+
+fn default_health_interval() -> u64 {
+    30
+}
+
+fn default_health_timeout() -> u64 {
+    5
+}
+
+fn default_health_url() -> String {
+    "http://www.gstatic.com/generate_204".to_string()
+}
+
+fn default_max_fails() -> u32 {
+    3
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            interval: default_health_interval(),
+            timeout: default_health_timeout(),
+            url: default_health_url(),
+            max_fails: default_max_fails(),
+        }
+    }
 }
 
 pub fn parse_conf(path: String) -> Result<Config> {
@@ -46,12 +249,13 @@ pub fn parse_conf(path: String) -> Result<Config> {
     return Ok(cfg);
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MatchMode {
     DomainSuffix,
     DomainKeyword,
     Domain,
     IpCidr,
+    User,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
@@ -66,17 +270,115 @@ pub enum Policy {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProxyGroup {
     pub id: String,
-    pub proxy_list: Vec<String>,
+    pub proxy_list: Vec<ProxyMember>,
+    // kind decides how proxies of group are picked.
+    #[serde(default, rename = "type")]
+    pub kind: ProxyGroupKind,
+    // strategy balances connections over the proxies when kind leaves the choice open.
+    #[serde(default)]
+    pub strategy: Strategy,
+    // url, interval and timeout configure the latency tests of url-test groups.
+    #[serde(default = "default_health_url")]
+    pub url: String,
+    #[serde(default = "default_url_test_interval")]
+    pub interval: u64,
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+    // tolerance is the milliseconds a proxy must beat the selected one by to replace it.
+    #[serde(default = "default_url_test_tolerance")]
+    pub tolerance: u64,
+    // cooldown is the seconds a member of fallback groups is skipped after failing.
+    #[serde(default = "default_fallback_cooldown")]
+    pub cooldown: u64,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+fn default_url_test_interval() -> u64 {
+    300
+}
+
+fn default_url_test_tolerance() -> u64 {
+    50
+}
+
+fn default_fallback_cooldown() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyGroupKind {
+    // Random picks any proxy alive.
+    #[default]
+    Random,
+    // UrlTest picks the proxy with the lowest latency to url.
+    UrlTest,
+    // Select picks the proxy selected through the manager, the first one at start.
+    Select,
+    // Fallback picks the first proxy not cooling down, the next ones are tried in
+    // order if connecting or the handshake to it fails. The reply goes out before the
+    // first read from the proxy, as clients usually speak first, so a stream whose
+    // first read fails isn't retried: the client sees it closed, and the proxy cools
+    // down for the next streams.
+    Fallback,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    // Random picks proxies at random.
+    #[default]
+    Random,
+    // RoundRobin picks proxies in turn.
+    RoundRobin,
+    // Weighted picks proxies at random in proportion to their weights.
+    Weighted,
+    // LeastConnections picks the proxy with the fewest open connections.
+    LeastConnections,
+    // ConsistentHash picks the same proxy for a destination domain or IP, as long as
+    // the proxies available stay the same.
+    ConsistentHash,
+}
+
+// ProxyMember is a proxy of a group, either its server id alone or with a weight.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ProxyMember {
+    Id(String),
+    Weighted {
+        id: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl ProxyMember {
+    pub fn id(&self) -> &str {
+        match self {
+            ProxyMember::Id(id) => id,
+            ProxyMember::Weighted { id, .. } => id,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        match self {
+            ProxyMember::Id(_) => default_weight(),
+            ProxyMember::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ACLConfig {
     pub rules: Vec<ProxyRule>,
     #[serde(rename = "final")]
     pub fnl: Policy,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProxyRule {
     pub pattern: Vec<String>,
     pub mode: MatchMode,
@@ -0,0 +1,245 @@
+use super::hkdf;
+use aes_gcm::aead::consts::{U12, U16};
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use bytes::{BufMut, BytesMut};
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+
+use super::interface::{Decrypto, Encrypto};
+
+// AeadEncrypto encrypts a stream with an AEAD cipher, the salt and subkey are as long as the key.
+pub struct AeadEncrypto<C> {
+    ase: C,
+    nonce: [u8; 12],
+    salt: Vec<u8>,
+}
+
+impl<C: AeadInPlace<NonceSize = U12> + NewAead> AeadEncrypto<C> {
+    pub fn new(secret_key: &[u8]) -> AeadEncrypto<C> {
+        let mut salt = vec![0; secret_key.len()];
+        random_salt(&mut salt[..]);
+
+        AeadEncrypto {
+            ase: new_subkey_cipher(&salt, secret_key),
+            nonce: [0u8; 12],
+            salt,
+        }
+    }
+}
+
+// new_subkey_cipher creates a cipher keyed by HKDF_SHA1(key, salt, "ss-subkey").
+fn new_subkey_cipher<C: NewAead>(salt: &[u8], secret_key: &[u8]) -> C {
+    let mut okm = vec![0u8; secret_key.len()];
+    hkdf::HkdfSha1::oneshot(salt, secret_key, HKDF_INFO, &mut okm);
+    C::new_varkey(&okm).expect("secret key length mismatches cipher")
+}
+
+/// Generate random bytes into `salt`
+pub fn random_salt(salt: &mut [u8]) {
+    if salt.is_empty() {
+        return;
+    }
+
+    let mut rng = rand::thread_rng();
+    loop {
+        rand::Rng::fill(&mut rng, salt);
+        let is_zeros = salt.iter().all(|&x| x == 0);
+        if !is_zeros {
+            break;
+        }
+    }
+}
+
+pub(super) const TAG_SIZE: usize = 16;
+
+// seal_in_place seals dst[start..] in place as one chunk and appends its tag.
+pub(super) fn seal_in_place<C: AeadInPlace<NonceSize = U12, TagSize = U16>>(
+    cipher: &C,
+    nonce: &mut [u8; 12],
+    dst: &mut BytesMut,
+    start: usize,
+) {
+    let tag = cipher
+        .encrypt_in_place_detached((&*nonce).into(), b"", &mut dst[start..])
+        .expect("chunk too large");
+    dst.extend_from_slice(&tag);
+    inc(nonce);
+}
+
+// open_in_place opens chunk sealed by seal_in_place and truncates its tag.
+pub(super) fn open_in_place<C: AeadInPlace<NonceSize = U12, TagSize = U16>>(
+    cipher: &C,
+    nonce: &mut [u8; 12],
+    chunk: &mut BytesMut,
+) -> Result<()> {
+    if chunk.len() < TAG_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid chunk length"));
+    }
+    let len = chunk.len() - TAG_SIZE;
+    let (data, tag) = chunk.split_at_mut(len);
+    let tag: &[u8; TAG_SIZE] = (&*tag).try_into().expect("tag length mismatches");
+    cipher
+        .decrypt_in_place_detached((&*nonce).into(), b"", data, tag.into())
+        .map_err(|_| auth_error())?;
+    chunk.truncate(len);
+    inc(nonce);
+    Ok(())
+}
+
+impl<C: AeadInPlace<NonceSize = U12, TagSize = U16>> Encrypto for AeadEncrypto<C> {
+    fn encrypt_init(&mut self, dst: &mut BytesMut) {
+        dst.extend_from_slice(&self.salt);
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8], dst: &mut BytesMut) {
+        dst.reserve(2 + plaintext.len() + 2 * TAG_SIZE);
+
+        let start = dst.len();
+        dst.put_u16(plaintext.len() as u16);
+        seal_in_place(&self.ase, &mut self.nonce, dst, start);
+
+        let start = dst.len();
+        dst.extend_from_slice(plaintext);
+        seal_in_place(&self.ase, &mut self.nonce, dst, start);
+    }
+
+    fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+}
+
+#[derive(Debug)]
+enum DecryptState {
+    Salt,
+    DataLen,
+    Data,
+}
+
+// AeadDecrypto decrypts a stream encrypted by AeadEncrypto.
+pub struct AeadDecrypto<C> {
+    ase: Option<C>,
+    nonce: [u8; 12],
+    secret_key: Vec<u8>,
+    salt: Vec<u8>,
+    datalen: usize,
+    state: DecryptState,
+}
+
+impl<C> AeadDecrypto<C> {
+    pub fn new(secret_key: &[u8]) -> AeadDecrypto<C> {
+        AeadDecrypto {
+            ase: None,
+            nonce: [0u8; 12],
+            secret_key: secret_key.to_vec(),
+            salt: Vec::new(),
+            datalen: 0,
+            state: DecryptState::Salt,
+        }
+    }
+}
+
+const HKDF_INFO: &[u8; 9] = b"ss-subkey";
+
+impl<C: AeadInPlace<NonceSize = U12, TagSize = U16>> AeadDecrypto<C> {
+    // open_chunk decrypts chunk in place, leaving the plaintext without tag.
+    fn open_chunk(&mut self, chunk: &mut BytesMut, expected_len: usize) -> Result<()> {
+        if chunk.len() != expected_len {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid chunk length"));
+        }
+        let ase = self
+            .ase
+            .as_ref()
+            .ok_or_else(|| Error::other("cipher isn't initialized"))?;
+        open_in_place(ase, &mut self.nonce, chunk)
+    }
+}
+
+// auth_error is returned when a chunk fails authentication.
+pub(super) fn auth_error() -> Error {
+    Error::new(ErrorKind::InvalidData, "chunk authentication failed")
+}
+
+impl<C: AeadInPlace<NonceSize = U12, TagSize = U16> + NewAead> Decrypto for AeadDecrypto<C> {
+    fn decrypt(&mut self, chunk: &mut BytesMut) -> Result<()> {
+        match self.state {
+            DecryptState::Salt => {
+                self.ase = Some(new_subkey_cipher(chunk, &self.secret_key));
+                self.salt = chunk.to_vec();
+                chunk.clear();
+                self.state = DecryptState::DataLen;
+            }
+            DecryptState::DataLen => {
+                self.open_chunk(chunk, 2 + TAG_SIZE)?;
+                self.datalen = ((chunk[0] as usize) << 8) + (chunk[1] as usize);
+                chunk.clear();
+                self.state = DecryptState::Data;
+            }
+            DecryptState::Data => {
+                self.open_chunk(chunk, self.datalen + TAG_SIZE)?;
+                self.state = DecryptState::DataLen;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_size(&self) -> usize {
+        match self.state {
+            DecryptState::Salt => self.secret_key.len(),
+            DecryptState::DataLen => 2 + TAG_SIZE,
+            DecryptState::Data => self.datalen + TAG_SIZE,
+        }
+    }
+
+    fn salt(&self) -> Option<&[u8]> {
+        match self.state {
+            DecryptState::Salt => None,
+            _ => Some(&self.salt),
+        }
+    }
+}
+
+// encrypt_packet seals a whole UDP datagram as [salt][payload][tag].
+// Every packet has its own salt, so the nonce is always zero.
+pub fn encrypt_packet<C: AeadInPlace<NonceSize = U12> + NewAead>(
+    secret_key: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let salt_size = secret_key.len();
+    let mut buf = Vec::with_capacity(salt_size + plaintext.len() + TAG_SIZE);
+    buf.resize(salt_size, 0);
+    random_salt(&mut buf[..salt_size]);
+    buf.extend_from_slice(plaintext);
+
+    let cipher: C = new_subkey_cipher(&buf[..salt_size], secret_key);
+    let tag = cipher
+        .encrypt_in_place_detached((&[0u8; 12]).into(), b"", &mut buf[salt_size..])
+        .map_err(|_| Error::other("packet too large"))?;
+    buf.extend_from_slice(&tag);
+    Ok(buf)
+}
+
+// decrypt_packet opens a UDP datagram sealed by encrypt_packet.
+pub fn decrypt_packet<C: AeadInPlace<NonceSize = U12> + NewAead>(
+    secret_key: &[u8],
+    packet: &[u8],
+) -> Result<Vec<u8>> {
+    let salt_size = secret_key.len();
+    if packet.len() < salt_size + TAG_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "packet too short"));
+    }
+    let cipher: C = new_subkey_cipher(&packet[..salt_size], secret_key);
+    let mut buf = packet[salt_size..].to_vec();
+    cipher
+        .decrypt_in_place((&[0u8; 12]).into(), b"", &mut buf)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "packet authentication failed"))?;
+    Ok(buf)
+}
+
+pub(super) fn inc(nonce: &mut [u8]) {
+    for i in &mut *nonce {
+        *i = ((*i as u16 + 1) % 256) as u8;
+        if *i != 0 {
+            return;
+        }
+    }
+}
@@ -0,0 +1,634 @@
+// Package aead2022 implements the shadowsocks 2022 (SIP022) AEAD ciphers.
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes::{BlockCipher, NewBlockCipher};
+use aes_gcm::aead::consts::{U12, U16};
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use bytes::{BufMut, BytesMut};
+use chacha20poly1305::XChaCha20Poly1305;
+use rand::Rng;
+
+use super::aead::{open_in_place, random_salt, seal_in_place, TAG_SIZE};
+use super::interface::{Decrypto, Encrypto};
+use crate::address;
+
+const SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+
+const HEADER_TYPE_CLIENT: u8 = 0;
+const HEADER_TYPE_SERVER: u8 = 1;
+
+// Peers with clocks differing by more than MAX_TIME_DIFF seconds are rejected.
+const MAX_TIME_DIFF: u64 = 30;
+const MAX_PADDING_LEN: usize = 900;
+const MAX_CHUNK_LEN: usize = 0xFFFF;
+
+// The request fixed-length header is formed as follows:
+//      +------+-----------+--------+
+//      | TYPE | TIMESTAMP | LENGTH |
+//      +------+-----------+--------+
+//      |  1   |     8     |   2    |
+//      +------+-----------+--------+
+// LENGTH is the length of the variable-length header:
+//      +------+----------+----------+--------+---------+---------+
+//      | ATYP | DST.ADDR | DST.PORT | PADLEN | PADDING | PAYLOAD |
+//      +------+----------+----------+--------+---------+---------+
+//      |  1   | Variable |    2     |   2    | Variable| Variable|
+//      +------+----------+----------+--------+---------+---------+
+const REQUEST_HEADER_LEN: usize = 1 + 8 + 2;
+
+// The response fixed-length header binds the response to its request by the request salt:
+//      +------+-----------+--------------+--------+
+//      | TYPE | TIMESTAMP | REQUEST SALT | LENGTH |
+//      +------+-----------+--------------+--------+
+//      |  1   |     8     |   Variable   |   2    |
+//      +------+-----------+--------------+--------+
+// LENGTH is the length of the first payload chunk following it.
+fn response_header_len(salt_len: usize) -> usize {
+    1 + 8 + salt_len + 2
+}
+
+// session_subkey derives a subkey as long as psk by BLAKE3(psk | salt).
+fn session_subkey(psk: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut subkey = vec![0u8; psk.len()];
+    let mut hasher = blake3::Hasher::new_derive_key(SUBKEY_CONTEXT);
+    hasher.update(psk);
+    hasher.update(salt);
+    hasher.finalize_xof().fill(&mut subkey);
+    subkey
+}
+
+fn new_subkey_cipher<C: NewAead>(psk: &[u8], salt: &[u8]) -> C {
+    C::new_varkey(&session_subkey(psk, salt)).expect("psk length mismatches cipher")
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn check_timestamp(timestamp: u64) -> Result<()> {
+    if unix_timestamp().abs_diff(timestamp) > MAX_TIME_DIFF {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("timestamp {} out of range", timestamp),
+        ));
+    }
+    Ok(())
+}
+
+fn read_u16(buf: &[u8]) -> usize {
+    u16::from_be_bytes([buf[0], buf[1]]) as usize
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(b)
+}
+
+// Aead2022Encrypto encrypts a request or response stream of shadowsocks 2022.
+pub struct Aead2022Encrypto<C> {
+    cipher: C,
+    nonce: [u8; 12],
+    salt: Vec<u8>,
+    // request_salt is set for a response stream.
+    request_salt: Option<Vec<u8>>,
+    header_sent: bool,
+}
+
+impl<C: AeadInPlace<NonceSize = U12, TagSize = U16> + NewAead> Aead2022Encrypto<C> {
+    pub fn new(psk: &[u8], request_salt: Option<&[u8]>) -> Aead2022Encrypto<C> {
+        let mut salt = vec![0; psk.len()];
+        random_salt(&mut salt[..]);
+
+        Aead2022Encrypto {
+            cipher: new_subkey_cipher(psk, &salt),
+            nonce: [0u8; 12],
+            salt,
+            request_salt: request_salt.map(|s| s.to_vec()),
+            header_sent: false,
+        }
+    }
+
+    // seal_chunk appends plaintext sealed as one chunk to dst.
+    fn seal_chunk(&mut self, plaintext: &[u8], dst: &mut BytesMut) {
+        let start = dst.len();
+        dst.extend_from_slice(plaintext);
+        seal_in_place(&self.cipher, &mut self.nonce, dst, start);
+    }
+
+    // seal_len appends the length of next chunk sealed to dst.
+    fn seal_len(&mut self, len: usize, dst: &mut BytesMut) {
+        self.seal_chunk(&(len as u16).to_be_bytes(), dst);
+    }
+
+    // seal_request_header seals the first write of a request stream, which starts with
+    // the target address and is padded when it carries no payload.
+    fn seal_request_header(&mut self, plaintext: &[u8], dst: &mut BytesMut) {
+        let addr_len = address::raw_address_len(plaintext).unwrap_or(plaintext.len());
+        let mut rng = rand::thread_rng();
+        let padding_len = if addr_len == plaintext.len() {
+            rng.gen_range(1..=MAX_PADDING_LEN)
+        } else {
+            0
+        };
+
+        // the payload exceeding the header is sealed as the next chunk.
+        let room = MAX_CHUNK_LEN - addr_len - 2 - padding_len;
+        let split = usize::min(plaintext.len(), addr_len + room);
+        let header_len = split + 2 + padding_len;
+        dst.reserve(REQUEST_HEADER_LEN + header_len + 2 * TAG_SIZE);
+
+        let start = dst.len();
+        dst.put_u8(HEADER_TYPE_CLIENT);
+        dst.put_u64(unix_timestamp());
+        dst.put_u16(header_len as u16);
+        seal_in_place(&self.cipher, &mut self.nonce, dst, start);
+
+        let start = dst.len();
+        dst.extend_from_slice(&plaintext[..addr_len]);
+        dst.put_u16(padding_len as u16);
+        let padding = dst.len();
+        dst.resize(padding + padding_len, 0);
+        rng.fill(&mut dst[padding..]);
+        dst.extend_from_slice(&plaintext[addr_len..split]);
+        seal_in_place(&self.cipher, &mut self.nonce, dst, start);
+
+        if split < plaintext.len() {
+            self.seal_len(plaintext.len() - split, dst);
+            self.seal_chunk(&plaintext[split..], dst);
+        }
+    }
+
+    // seal_response_header seals the first write of a response stream.
+    fn seal_response_header(&mut self, plaintext: &[u8], request_salt: &[u8], dst: &mut BytesMut) {
+        dst.reserve(response_header_len(request_salt.len()) + plaintext.len() + 2 * TAG_SIZE);
+
+        let start = dst.len();
+        dst.put_u8(HEADER_TYPE_SERVER);
+        dst.put_u64(unix_timestamp());
+        dst.extend_from_slice(request_salt);
+        dst.put_u16(plaintext.len() as u16);
+        seal_in_place(&self.cipher, &mut self.nonce, dst, start);
+        self.seal_chunk(plaintext, dst);
+    }
+}
+
+impl<C: AeadInPlace<NonceSize = U12, TagSize = U16> + NewAead> Encrypto for Aead2022Encrypto<C> {
+    fn encrypt_init(&mut self, dst: &mut BytesMut) {
+        dst.extend_from_slice(&self.salt);
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8], dst: &mut BytesMut) {
+        if self.header_sent {
+            dst.reserve(2 + plaintext.len() + 2 * TAG_SIZE);
+            self.seal_len(plaintext.len(), dst);
+            self.seal_chunk(plaintext, dst);
+            return;
+        }
+
+        self.header_sent = true;
+        match self.request_salt.take() {
+            Some(request_salt) => self.seal_response_header(plaintext, &request_salt, dst),
+            None => self.seal_request_header(plaintext, dst),
+        }
+    }
+
+    fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+}
+
+#[derive(Debug)]
+enum DecryptState {
+    Salt,
+    FixedHeader,
+    Header,
+    DataLen,
+    Data,
+}
+
+// Aead2022Decrypto decrypts a stream encrypted by Aead2022Encrypto.
+pub struct Aead2022Decrypto<C> {
+    cipher: Option<C>,
+    nonce: [u8; 12],
+    psk: Vec<u8>,
+    salt: Vec<u8>,
+    // request_salt is set for a response stream, which must carry it in its header.
+    request_salt: Option<Vec<u8>>,
+    datalen: usize,
+    state: DecryptState,
+}
+
+impl<C> Aead2022Decrypto<C> {
+    pub fn new(psk: &[u8], request_salt: Option<&[u8]>) -> Aead2022Decrypto<C> {
+        Aead2022Decrypto {
+            cipher: None,
+            nonce: [0u8; 12],
+            psk: psk.to_vec(),
+            salt: Vec::new(),
+            request_salt: request_salt.map(|s| s.to_vec()),
+            datalen: 0,
+            state: DecryptState::Salt,
+        }
+    }
+}
+
+impl<C: AeadInPlace<NonceSize = U12, TagSize = U16>> Aead2022Decrypto<C> {
+    fn open_chunk(&mut self, chunk: &mut BytesMut, expected_len: usize) -> Result<()> {
+        if chunk.len() != expected_len {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid chunk length"));
+        }
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| Error::other("cipher isn't initialized"))?;
+        open_in_place(cipher, &mut self.nonce, chunk)
+    }
+
+    // open_fixed_header checks the fixed-length header and returns the length of next chunk.
+    fn open_fixed_header(&mut self, chunk: &mut BytesMut) -> Result<usize> {
+        let (expected_type, header_len) = match &self.request_salt {
+            Some(salt) => (HEADER_TYPE_SERVER, response_header_len(salt.len())),
+            None => (HEADER_TYPE_CLIENT, REQUEST_HEADER_LEN),
+        };
+        self.open_chunk(chunk, header_len + TAG_SIZE)?;
+        if chunk[0] != expected_type {
+            return Err(Error::new(ErrorKind::InvalidData, "unexpected header type"));
+        }
+        check_timestamp(read_u64(&chunk[1..]))?;
+        if let Some(request_salt) = &self.request_salt {
+            if &chunk[9..9 + request_salt.len()] != request_salt.as_slice() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "response to another request",
+                ));
+            }
+        }
+        Ok(read_u16(&chunk[chunk.len() - 2..]))
+    }
+
+    // open_request_header strips the padding of variable-length header, leaving
+    // the target address followed by the initial payload.
+    fn open_request_header(&mut self, chunk: &mut BytesMut) -> Result<()> {
+        self.open_chunk(chunk, self.datalen + TAG_SIZE)?;
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid request header");
+        let addr_len = address::raw_address_len(chunk).map_err(|_| invalid())?;
+        if chunk.len() < addr_len + 2 {
+            return Err(invalid());
+        }
+        let padding_len = read_u16(&chunk[addr_len..]);
+        let payload = addr_len + 2 + padding_len;
+        if chunk.len() < payload {
+            return Err(invalid());
+        }
+        chunk.copy_within(payload.., addr_len);
+        chunk.truncate(chunk.len() - 2 - padding_len);
+        Ok(())
+    }
+}
+
+impl<C: AeadInPlace<NonceSize = U12, TagSize = U16> + NewAead> Decrypto for Aead2022Decrypto<C> {
+    fn decrypt(&mut self, chunk: &mut BytesMut) -> Result<()> {
+        match self.state {
+            DecryptState::Salt => {
+                self.cipher = Some(new_subkey_cipher(&self.psk, chunk));
+                self.salt = chunk.to_vec();
+                chunk.clear();
+                self.state = DecryptState::FixedHeader;
+            }
+            DecryptState::FixedHeader => {
+                self.datalen = self.open_fixed_header(chunk)?;
+                chunk.clear();
+                self.state = DecryptState::Header;
+            }
+            DecryptState::Header => {
+                self.state = DecryptState::DataLen;
+                if self.request_salt.is_some() {
+                    return self.open_chunk(chunk, self.datalen + TAG_SIZE);
+                }
+                return self.open_request_header(chunk);
+            }
+            DecryptState::DataLen => {
+                self.open_chunk(chunk, 2 + TAG_SIZE)?;
+                self.datalen = read_u16(chunk);
+                chunk.clear();
+                self.state = DecryptState::Data;
+            }
+            DecryptState::Data => {
+                self.open_chunk(chunk, self.datalen + TAG_SIZE)?;
+                self.state = DecryptState::DataLen;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_size(&self) -> usize {
+        match self.state {
+            DecryptState::Salt => self.psk.len(),
+            DecryptState::FixedHeader => match &self.request_salt {
+                Some(salt) => response_header_len(salt.len()) + TAG_SIZE,
+                None => REQUEST_HEADER_LEN + TAG_SIZE,
+            },
+            DecryptState::Header | DecryptState::Data => self.datalen + TAG_SIZE,
+            DecryptState::DataLen => 2 + TAG_SIZE,
+        }
+    }
+
+    fn salt(&self) -> Option<&[u8]> {
+        match self.state {
+            DecryptState::Salt => None,
+            _ => Some(&self.salt),
+        }
+    }
+}
+
+// UdpSession is the shadowsocks 2022 state of one UDP association.
+#[derive(Debug)]
+pub struct UdpSession {
+    session_id: u64,
+    packet_id: AtomicU64,
+    // client_session_id is the client session answered by a server session.
+    client_session_id: Option<u64>,
+}
+
+impl UdpSession {
+    // UdpSession::new creates a client session with a random session id.
+    pub fn new() -> UdpSession {
+        UdpSession {
+            session_id: rand::random(),
+            packet_id: AtomicU64::new(0),
+            client_session_id: None,
+        }
+    }
+
+    // UdpSession::new_server creates a server session answering client_session_id.
+    pub fn new_server(client_session_id: u64) -> UdpSession {
+        UdpSession {
+            client_session_id: Some(client_session_id),
+            ..UdpSession::new()
+        }
+    }
+
+    // id returns the session id.
+    pub fn id(&self) -> u64 {
+        self.session_id
+    }
+
+    fn next_packet_id(&self) -> u64 {
+        self.packet_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // A packet is sealed from the main header formed as follows:
+    //      +------+-----------+-------------------+--------+---------+------+---------+
+    //      | TYPE | TIMESTAMP | CLIENT SESSION ID | PADLEN | PADDING | ADDR | PAYLOAD |
+    //      +------+-----------+-------------------+--------+---------+------+---------+
+    //      |  1   |     8     |         8         |   2    | Variable| Var. | Variable|
+    //      +------+-----------+-------------------+--------+---------+------+---------+
+    // CLIENT SESSION ID is only present in packets sent by servers.
+    fn put_main_header(&self, buf: &mut Vec<u8>) {
+        match self.client_session_id {
+            Some(id) => {
+                buf.push(HEADER_TYPE_SERVER);
+                buf.extend_from_slice(&unix_timestamp().to_be_bytes());
+                buf.extend_from_slice(&id.to_be_bytes());
+            }
+            None => {
+                buf.push(HEADER_TYPE_CLIENT);
+                buf.extend_from_slice(&unix_timestamp().to_be_bytes());
+            }
+        }
+        buf.extend_from_slice(&[0, 0]);
+    }
+}
+
+impl Default for UdpSession {
+    fn default() -> UdpSession {
+        UdpSession::new()
+    }
+}
+
+// PacketHeader identifies a shadowsocks 2022 datagram.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacketHeader {
+    pub session_id: u64,
+    pub packet_id: u64,
+    // client_session_id is the client session answered by a server packet.
+    pub client_session_id: Option<u64>,
+}
+
+// skip_main_header checks the main header of a packet sent by a server if from_server, or
+// else by a client, and returns its length and the client session id of server packets.
+fn skip_main_header(body: &[u8], from_server: bool) -> Result<(usize, Option<u64>)> {
+    let too_short = || Error::new(ErrorKind::InvalidData, "packet too short");
+    let mut pos = match (body.first(), from_server) {
+        (Some(&HEADER_TYPE_CLIENT), false) => 1 + 8,
+        (Some(&HEADER_TYPE_SERVER), true) => 1 + 8 + 8,
+        (Some(_), _) => return Err(Error::new(ErrorKind::InvalidData, "unexpected header type")),
+        (None, _) => return Err(too_short()),
+    };
+    if body.len() < pos + 2 {
+        return Err(too_short());
+    }
+    check_timestamp(read_u64(&body[1..]))?;
+    let client_session_id = if from_server {
+        Some(read_u64(&body[9..]))
+    } else {
+        None
+    };
+    pos += 2 + read_u16(&body[pos..]);
+    if body.len() < pos {
+        return Err(too_short());
+    }
+    Ok((pos, client_session_id))
+}
+
+// Packets of a session are accepted once, up to this many behind the newest one.
+const REPLAY_WINDOW_LEN: u64 = 1024;
+
+// ReplayWindow is the sliding window of packet ids seen in a session, rejecting packets
+// replayed or too old to tell.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    // newest is the highest packet id seen, None before the first packet.
+    newest: Option<u64>,
+    // seen has the bit id % REPLAY_WINDOW_LEN set for the ids seen within the window.
+    seen: [u64; (REPLAY_WINDOW_LEN / 64) as usize],
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow {
+            newest: None,
+            seen: [0; (REPLAY_WINDOW_LEN / 64) as usize],
+        }
+    }
+
+    fn bit(id: u64) -> (usize, u64) {
+        let idx = id % REPLAY_WINDOW_LEN;
+        ((idx / 64) as usize, 1 << (idx % 64))
+    }
+
+    // check records packet id and returns whether it wasn't seen before.
+    pub fn check(&mut self, id: u64) -> bool {
+        match self.newest {
+            Some(newest) if id <= newest => {
+                if newest - id >= REPLAY_WINDOW_LEN {
+                    return false;
+                }
+                let (word, mask) = ReplayWindow::bit(id);
+                if self.seen[word] & mask != 0 {
+                    return false;
+                }
+                self.seen[word] |= mask;
+                true
+            }
+            newest => {
+                // ids skipped over are forgotten as the window slides.
+                match newest {
+                    Some(newest) if id - newest < REPLAY_WINDOW_LEN => {
+                        for skipped in newest + 1..id {
+                            let (word, mask) = ReplayWindow::bit(skipped);
+                            self.seen[word] &= !mask;
+                        }
+                    }
+                    _ => self.seen = [0; (REPLAY_WINDOW_LEN / 64) as usize],
+                }
+                let (word, mask) = ReplayWindow::bit(id);
+                self.seen[word] |= mask;
+                self.newest = Some(id);
+                true
+            }
+        }
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> ReplayWindow {
+        ReplayWindow::new()
+    }
+}
+
+// encrypt_packet seals a UDP datagram as [separate header][body][tag]. The separate header
+// of session id and packet id is encrypted with psk by the block cipher B, and the body is
+// sealed by the session subkey with the last 12 bytes of the separate header as nonce.
+pub fn encrypt_packet<C, B>(psk: &[u8], session: &UdpSession, plaintext: &[u8]) -> Result<Vec<u8>>
+where
+    C: AeadInPlace<NonceSize = U12> + NewAead,
+    B: BlockCipher<BlockSize = U16> + NewBlockCipher,
+{
+    let mut separate = [0u8; 16];
+    separate[..8].copy_from_slice(&session.session_id.to_be_bytes());
+    separate[8..].copy_from_slice(&session.next_packet_id().to_be_bytes());
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&separate[4..]);
+
+    let mut buf = Vec::with_capacity(16 + 19 + plaintext.len() + TAG_SIZE);
+    buf.extend_from_slice(&separate);
+    session.put_main_header(&mut buf);
+    buf.extend_from_slice(plaintext);
+
+    let cipher: C = new_subkey_cipher(psk, &separate[..8]);
+    let tag = cipher
+        .encrypt_in_place_detached((&nonce).into(), b"", &mut buf[16..])
+        .map_err(|_| Error::other("packet too large"))?;
+    buf.extend_from_slice(&tag);
+
+    let block_cipher = B::new_varkey(psk).map_err(|_| Error::other("invalid psk length"))?;
+    block_cipher.encrypt_block((&mut separate).into());
+    buf[..16].copy_from_slice(&separate);
+    Ok(buf)
+}
+
+// decrypt_packet opens a UDP datagram sealed by encrypt_packet, sent by a server if
+// from_server, returning the address and payload with the header of packet.
+pub fn decrypt_packet<C, B>(
+    psk: &[u8],
+    packet: &[u8],
+    from_server: bool,
+) -> Result<(Vec<u8>, PacketHeader)>
+where
+    C: AeadInPlace<NonceSize = U12> + NewAead,
+    B: BlockCipher<BlockSize = U16> + NewBlockCipher,
+{
+    if packet.len() < 16 + TAG_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "packet too short"));
+    }
+    let mut separate = [0u8; 16];
+    separate.copy_from_slice(&packet[..16]);
+    let block_cipher = B::new_varkey(psk).map_err(|_| Error::other("invalid psk length"))?;
+    block_cipher.decrypt_block((&mut separate).into());
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&separate[4..]);
+
+    let cipher: C = new_subkey_cipher(psk, &separate[..8]);
+    let mut body = packet[16..].to_vec();
+    cipher
+        .decrypt_in_place((&nonce).into(), b"", &mut body)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "packet authentication failed"))?;
+
+    let (header_len, client_session_id) = skip_main_header(&body, from_server)?;
+    let header = PacketHeader {
+        session_id: read_u64(&separate),
+        packet_id: read_u64(&separate[8..]),
+        client_session_id,
+    };
+    Ok((body.split_off(header_len), header))
+}
+
+// encrypt_packet_xchacha seals a UDP datagram as [nonce][body][tag] by XChaCha20-Poly1305
+// with psk, the session id and packet id lead the body.
+pub fn encrypt_packet_xchacha(
+    psk: &[u8],
+    session: &UdpSession,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(24 + 16 + 19 + plaintext.len() + TAG_SIZE);
+    buf.resize(24, 0);
+    rand::thread_rng().fill(&mut buf[..24]);
+    buf.extend_from_slice(&session.session_id.to_be_bytes());
+    buf.extend_from_slice(&session.next_packet_id().to_be_bytes());
+    session.put_main_header(&mut buf);
+    buf.extend_from_slice(plaintext);
+
+    let mut nonce = [0u8; 24];
+    nonce.copy_from_slice(&buf[..24]);
+    let cipher =
+        XChaCha20Poly1305::new_varkey(psk).map_err(|_| Error::other("invalid psk length"))?;
+    let tag = cipher
+        .encrypt_in_place_detached((&nonce).into(), b"", &mut buf[24..])
+        .map_err(|_| Error::other("packet too large"))?;
+    buf.extend_from_slice(&tag);
+    Ok(buf)
+}
+
+// decrypt_packet_xchacha opens a UDP datagram sealed by encrypt_packet_xchacha.
+pub fn decrypt_packet_xchacha(
+    psk: &[u8],
+    packet: &[u8],
+    from_server: bool,
+) -> Result<(Vec<u8>, PacketHeader)> {
+    if packet.len() < 24 + 16 + TAG_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "packet too short"));
+    }
+    let mut nonce = [0u8; 24];
+    nonce.copy_from_slice(&packet[..24]);
+    let cipher =
+        XChaCha20Poly1305::new_varkey(psk).map_err(|_| Error::other("invalid psk length"))?;
+    let mut body = packet[24..].to_vec();
+    cipher
+        .decrypt_in_place((&nonce).into(), b"", &mut body)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "packet authentication failed"))?;
+
+    let (header_len, client_session_id) = skip_main_header(&body[16..], from_server)?;
+    let header = PacketHeader {
+        session_id: read_u64(&body),
+        packet_id: read_u64(&body[8..]),
+        client_session_id,
+    };
+    Ok((body.split_off(16 + header_len), header))
+}
@@ -0,0 +1,131 @@
+// Package bloom implements a ping-pong bloom filter of recently seen salts.
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+
+// BloomFilter is a standard bloom filter, its hash functions are SipHash seeded by index.
+struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u64,
+    count: usize,
+    hasher: RandomState,
+}
+
+impl BloomFilter {
+    // BloomFilter::new sizes the filter for capacity items at fp_rate false positive rate.
+    fn new(capacity: usize, fp_rate: f64) -> BloomFilter {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * fp_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / capacity as f64) * ln2).round() as u64;
+
+        BloomFilter {
+            bits: vec![0u64; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes: num_hashes.max(1),
+            count: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn positions<'a>(&'a self, item: &'a [u8]) -> impl Iterator<Item = u64> + 'a {
+        (0..self.num_hashes).map(move |i| self.hasher.hash_one((i, item)) % self.num_bits)
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|p| self.bits[(p / 64) as usize] & (1 << (p % 64)) != 0)
+    }
+
+    fn insert(&mut self, item: &[u8]) {
+        let positions: Vec<u64> = self.positions(item).collect();
+        for p in positions {
+            self.bits[(p / 64) as usize] |= 1 << (p % 64);
+        }
+        self.count += 1;
+    }
+
+    fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|b| *b = 0);
+        self.count = 0;
+    }
+}
+
+// PingPong holds two filters, new items go to the current one. Once it's full
+// the other filter is cleared and becomes current, so the filter always
+// remembers the last capacity items at least.
+struct PingPong {
+    filters: [BloomFilter; 2],
+    current: usize,
+    capacity: usize,
+}
+
+// ReplayFilter tracks recently seen salts to reject replayed streams.
+pub struct ReplayFilter {
+    inner: Mutex<PingPong>,
+}
+
+impl ReplayFilter {
+    // ReplayFilter::new creates a filter remembering capacity salts at least with
+    // the false positive rate fp_rate.
+    pub fn new(capacity: usize, fp_rate: f64) -> Result<ReplayFilter> {
+        if capacity == 0 || !(fp_rate > 0.0 && fp_rate < 1.0) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "replay filter needs a positive capacity and a false positive rate in (0, 1)",
+            ));
+        }
+
+        Ok(ReplayFilter {
+            inner: Mutex::new(PingPong {
+                filters: [
+                    BloomFilter::new(capacity, fp_rate),
+                    BloomFilter::new(capacity, fp_rate),
+                ],
+                current: 0,
+                capacity,
+            }),
+        })
+    }
+
+    // check_and_insert returns false if salt has been seen, otherwise it remembers salt.
+    pub fn check_and_insert(&self, salt: &[u8]) -> bool {
+        let mut pp = self.inner.lock().unwrap();
+        if pp.filters.iter().any(|f| f.contains(salt)) {
+            return false;
+        }
+
+        if pp.filters[pp.current].count >= pp.capacity {
+            pp.current ^= 1;
+            let current = pp.current;
+            pp.filters[current].clear();
+        }
+        let current = pp.current;
+        pp.filters[current].insert(salt);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_filter() {
+        let filter = ReplayFilter::new(100, 1e-6).unwrap();
+        assert!(filter.check_and_insert(b"salt"));
+        assert!(!filter.check_and_insert(b"salt"));
+
+        // salt is forgotten after both filters are rotated.
+        for i in 0..200u32 {
+            assert!(filter.check_and_insert(&i.to_be_bytes()));
+        }
+        assert!(filter.check_and_insert(b"salt"));
+        assert!(!filter.check_and_insert(&199u32.to_be_bytes()));
+
+        assert!(ReplayFilter::new(0, 1e-6).is_err());
+        assert!(ReplayFilter::new(100, 1.0).is_err());
+    }
+}
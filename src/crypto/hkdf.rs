@@ -92,7 +92,6 @@ impl HkdfSha1 {
             // NOTE: 允许最后一个 Block 不是完整长度的输出。
             let last_okm = &mut okm[n * Self::TAG_LEN..];
             let len = core::cmp::min(last_okm.len(), Self::TAG_LEN);
-            println!("{:#02x?}", &t[..len]);
             last_okm[..len].copy_from_slice(&t[..len]);
         }
     }
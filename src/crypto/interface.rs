@@ -1,10 +1,27 @@
+use std::io::Result;
+
+use bytes::BytesMut;
+
+// Encrypto seals a stream into chunks appended to a caller-provided buffer, so that
+// a writer can reuse one buffer for the whole stream. Implement it to plug a cipher
+// into CryptoWriter::with_encrypto.
 pub trait Encrypto {
-    fn encrypt_init(&mut self) -> &Vec<u8>;
-    fn encrypt(&mut self, plaintext: &[u8]) -> &Vec<u8>;
-    fn reset(&mut self);
+    // encrypt_init appends the salt leading the stream to dst.
+    fn encrypt_init(&mut self, dst: &mut BytesMut);
+    // encrypt appends plaintext sealed in place as one or more chunks to dst.
+    fn encrypt(&mut self, plaintext: &[u8], dst: &mut BytesMut);
+    // salt returns the salt leading the stream.
+    fn salt(&self) -> &[u8];
 }
 
+// Decrypto opens a stream chunk by chunk, each chunk is next_size bytes long.
+// Implement it to plug a cipher into CryptoReader::with_decrypto.
 pub trait Decrypto {
-    fn decrypt(&mut self, chiper: &mut Vec<u8>) -> usize;
-    fn next_size(&mut self) -> usize;
+    // decrypt opens chunk in place and truncates it to its payload, which is empty
+    // unless it's a data chunk.
+    fn decrypt(&mut self, chunk: &mut BytesMut) -> Result<()>;
+    // next_size returns the length of next chunk, the salt is the first one.
+    fn next_size(&self) -> usize;
+    // salt returns the salt of stream once it's opened.
+    fn salt(&self) -> Option<&[u8]>;
 }
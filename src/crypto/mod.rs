@@ -1,40 +1,416 @@
-mod aes;
+mod aead;
+mod aead2022;
+mod bloom;
 mod hkdf;
 mod interface;
 
+use std::fmt;
 use std::io::{Error, ErrorKind};
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 
+use aes::{Aes128, Aes256};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::ChaCha20Poly1305;
 use crypto::digest::Digest;
 use crypto::md5::Md5;
 use futures::ready;
 use tokio::io;
 use tokio::io::ReadBuf;
+use tokio_util::io::poll_read_buf;
+
+pub use aead2022::{PacketHeader, ReplayWindow, UdpSession};
+pub use bloom::ReplayFilter;
+pub use interface::{Decrypto, Encrypto};
+
+// CipherKind is an AEAD cipher method of shadowsocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CipherKind {
+    #[default]
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20IetfPoly1305,
+    Blake3Aes128Gcm,
+    Blake3Aes256Gcm,
+    Blake3Chacha20Poly1305,
+}
+
+// CipherInfo describes a method of the cipher registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CipherInfo {
+    pub kind: CipherKind,
+    // method is the name used in config.
+    pub method: &'static str,
+    pub key_len: usize,
+    pub salt_len: usize,
+    pub tag_len: usize,
+}
+
+// CIPHERS is the registry of supported methods.
+pub const CIPHERS: [CipherInfo; 6] = [
+    CipherInfo {
+        kind: CipherKind::Aes128Gcm,
+        method: "aes-128-gcm",
+        key_len: 16,
+        salt_len: 16,
+        tag_len: aead::TAG_SIZE,
+    },
+    CipherInfo {
+        kind: CipherKind::Aes256Gcm,
+        method: "aes-256-gcm",
+        key_len: 32,
+        salt_len: 32,
+        tag_len: aead::TAG_SIZE,
+    },
+    CipherInfo {
+        kind: CipherKind::Chacha20IetfPoly1305,
+        method: "chacha20-ietf-poly1305",
+        key_len: 32,
+        salt_len: 32,
+        tag_len: aead::TAG_SIZE,
+    },
+    CipherInfo {
+        kind: CipherKind::Blake3Aes128Gcm,
+        method: "2022-blake3-aes-128-gcm",
+        key_len: 16,
+        salt_len: 16,
+        tag_len: aead::TAG_SIZE,
+    },
+    CipherInfo {
+        kind: CipherKind::Blake3Aes256Gcm,
+        method: "2022-blake3-aes-256-gcm",
+        key_len: 32,
+        salt_len: 32,
+        tag_len: aead::TAG_SIZE,
+    },
+    CipherInfo {
+        kind: CipherKind::Blake3Chacha20Poly1305,
+        method: "2022-blake3-chacha20-poly1305",
+        key_len: 32,
+        salt_len: 32,
+        tag_len: aead::TAG_SIZE,
+    },
+];
+
+impl FromStr for CipherKind {
+    type Err = Error;
+
+    // from_str parses the method name used in config.
+    fn from_str(method: &str) -> io::Result<CipherKind> {
+        CIPHERS
+            .iter()
+            .find(|c| c.method == method)
+            .map(|c| c.kind)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unsupported method {}", method),
+                )
+            })
+    }
+}
+
+impl fmt::Display for CipherKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.info().method)
+    }
+}
+
+impl CipherKind {
+    // info returns the registry entry of the method.
+    pub fn info(&self) -> &'static CipherInfo {
+        CIPHERS
+            .iter()
+            .find(|c| c.kind == *self)
+            .expect("cipher isn't registered")
+    }
+
+    // key_len returns the length of key.
+    pub fn key_len(&self) -> usize {
+        self.info().key_len
+    }
+
+    // salt_len returns the length of salt leading a stream or packet.
+    pub fn salt_len(&self) -> usize {
+        self.info().salt_len
+    }
+
+    // tag_len returns the length of tag following each chunk.
+    pub fn tag_len(&self) -> usize {
+        self.info().tag_len
+    }
+
+    // max_payload_len returns the largest payload of a chunk.
+    pub fn max_payload_len(&self) -> usize {
+        if self.is_2022() {
+            0xFFFF
+        } else {
+            0x3FFF
+        }
+    }
+
+    // is_2022 reports whether it's a shadowsocks 2022 method.
+    pub fn is_2022(&self) -> bool {
+        matches!(
+            self,
+            CipherKind::Blake3Aes128Gcm
+                | CipherKind::Blake3Aes256Gcm
+                | CipherKind::Blake3Chacha20Poly1305
+        )
+    }
+
+    // derive_key returns the key of password. Shadowsocks 2022 methods take a base64
+    // encoded key as password, while legacy ones derive it by evp_bytes_to_key.
+    pub fn derive_key(&self, password: &str) -> io::Result<Vec<u8>> {
+        if !self.is_2022() {
+            return Ok(evp_bytes_to_key(password.to_string(), self.key_len()));
+        }
+
+        let key = base64::decode(password)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "password isn't base64 encoded"))?;
+        if key.len() != self.key_len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("password must be a {} bytes key", self.key_len()),
+            ));
+        }
+        Ok(key)
+    }
+
+    // new_encrypto creates the encrypto of a request stream, or of a response stream
+    // if request_salt is given.
+    pub fn new_encrypto(
+        self,
+        secret_key: &[u8],
+        request_salt: Option<&[u8]>,
+    ) -> Box<dyn Encrypto + Send> {
+        match self {
+            CipherKind::Aes128Gcm => Box::new(aead::AeadEncrypto::<Aes128Gcm>::new(secret_key)),
+            CipherKind::Aes256Gcm => Box::new(aead::AeadEncrypto::<Aes256Gcm>::new(secret_key)),
+            CipherKind::Chacha20IetfPoly1305 => {
+                Box::new(aead::AeadEncrypto::<ChaCha20Poly1305>::new(secret_key))
+            }
+            CipherKind::Blake3Aes128Gcm => Box::new(aead2022::Aead2022Encrypto::<Aes128Gcm>::new(
+                secret_key,
+                request_salt,
+            )),
+            CipherKind::Blake3Aes256Gcm => Box::new(aead2022::Aead2022Encrypto::<Aes256Gcm>::new(
+                secret_key,
+                request_salt,
+            )),
+            CipherKind::Blake3Chacha20Poly1305 => Box::new(aead2022::Aead2022Encrypto::<
+                ChaCha20Poly1305,
+            >::new(
+                secret_key, request_salt
+            )),
+        }
+    }
+
+    // new_decrypto creates the decrypto of a request stream, or of a response stream
+    // if request_salt is given.
+    pub fn new_decrypto(
+        self,
+        secret_key: &[u8],
+        request_salt: Option<&[u8]>,
+    ) -> Box<dyn Decrypto + Send> {
+        match self {
+            CipherKind::Aes128Gcm => Box::new(aead::AeadDecrypto::<Aes128Gcm>::new(secret_key)),
+            CipherKind::Aes256Gcm => Box::new(aead::AeadDecrypto::<Aes256Gcm>::new(secret_key)),
+            CipherKind::Chacha20IetfPoly1305 => {
+                Box::new(aead::AeadDecrypto::<ChaCha20Poly1305>::new(secret_key))
+            }
+            CipherKind::Blake3Aes128Gcm => Box::new(aead2022::Aead2022Decrypto::<Aes128Gcm>::new(
+                secret_key,
+                request_salt,
+            )),
+            CipherKind::Blake3Aes256Gcm => Box::new(aead2022::Aead2022Decrypto::<Aes256Gcm>::new(
+                secret_key,
+                request_salt,
+            )),
+            CipherKind::Blake3Chacha20Poly1305 => Box::new(aead2022::Aead2022Decrypto::<
+                ChaCha20Poly1305,
+            >::new(
+                secret_key, request_salt
+            )),
+        }
+    }
+}
+
+// encrypt_packet encrypts a UDP datagram for mika server or client.
+// session is only used by shadowsocks 2022 methods.
+pub fn encrypt_packet(
+    kind: CipherKind,
+    secret_key: &[u8],
+    session: &UdpSession,
+    plaintext: &[u8],
+) -> io::Result<Vec<u8>> {
+    match kind {
+        CipherKind::Aes128Gcm => aead::encrypt_packet::<Aes128Gcm>(secret_key, plaintext),
+        CipherKind::Aes256Gcm => aead::encrypt_packet::<Aes256Gcm>(secret_key, plaintext),
+        CipherKind::Chacha20IetfPoly1305 => {
+            aead::encrypt_packet::<ChaCha20Poly1305>(secret_key, plaintext)
+        }
+        CipherKind::Blake3Aes128Gcm => {
+            aead2022::encrypt_packet::<Aes128Gcm, Aes128>(secret_key, session, plaintext)
+        }
+        CipherKind::Blake3Aes256Gcm => {
+            aead2022::encrypt_packet::<Aes256Gcm, Aes256>(secret_key, session, plaintext)
+        }
+        CipherKind::Blake3Chacha20Poly1305 => {
+            aead2022::encrypt_packet_xchacha(secret_key, session, plaintext)
+        }
+    }
+}
+
+// decrypt_packet decrypts a UDP datagram encrypted by encrypt_packet, sent by a server
+// if from_server or else by a client. It also returns the header of shadowsocks 2022
+// packets, whose sender is checked against from_server.
+pub fn decrypt_packet(
+    kind: CipherKind,
+    secret_key: &[u8],
+    packet: &[u8],
+    from_server: bool,
+) -> io::Result<(Vec<u8>, Option<PacketHeader>)> {
+    let (plaintext, header) = match kind {
+        CipherKind::Aes128Gcm => (aead::decrypt_packet::<Aes128Gcm>(secret_key, packet)?, None),
+        CipherKind::Aes256Gcm => (aead::decrypt_packet::<Aes256Gcm>(secret_key, packet)?, None),
+        CipherKind::Chacha20IetfPoly1305 => (
+            aead::decrypt_packet::<ChaCha20Poly1305>(secret_key, packet)?,
+            None,
+        ),
+        CipherKind::Blake3Aes128Gcm => {
+            let (p, header) =
+                aead2022::decrypt_packet::<Aes128Gcm, Aes128>(secret_key, packet, from_server)?;
+            (p, Some(header))
+        }
+        CipherKind::Blake3Aes256Gcm => {
+            let (p, header) =
+                aead2022::decrypt_packet::<Aes256Gcm, Aes256>(secret_key, packet, from_server)?;
+            (p, Some(header))
+        }
+        CipherKind::Blake3Chacha20Poly1305 => {
+            let (p, header) = aead2022::decrypt_packet_xchacha(secret_key, packet, from_server)?;
+            (p, Some(header))
+        }
+    };
+    Ok((plaintext, header))
+}
 
 pub struct CryptoWriter<T>
 where
     T: io::AsyncWrite + std::marker::Unpin,
 {
-    crypto: Box<dyn interface::Encrypto + Send>,
+    crypto: Box<dyn Encrypto + Send>,
     writer: T,
     inited: bool,
+    max_payload_len: usize,
+    // buf holds sealed chunks not yet written.
+    buf: BytesMut,
+    // coalesce buffers small writes in plain until flush or a full chunk.
+    coalesce: bool,
+    plain: BytesMut,
 }
 
 impl<T> CryptoWriter<T>
 where
     T: io::AsyncWrite + std::marker::Unpin,
 {
-    pub fn new(writer: T, secret_key: &[u8]) -> CryptoWriter<T> {
-        let crypto: Box<dyn interface::Encrypto + Send> =
-            Box::new(aes::Aes128Gcm0::new(secret_key));
+    // CryptoWriter::new creates a writer of request stream.
+    pub fn new(writer: T, kind: CipherKind, secret_key: &[u8]) -> CryptoWriter<T> {
+        Self::with_encrypto(
+            writer,
+            kind.new_encrypto(secret_key, None),
+            kind.max_payload_len(),
+        )
+    }
+
+    // CryptoWriter::new_response creates a writer of the response to a request stream
+    // with request_salt.
+    pub fn new_response(
+        writer: T,
+        kind: CipherKind,
+        secret_key: &[u8],
+        request_salt: &[u8],
+    ) -> CryptoWriter<T> {
+        Self::with_encrypto(
+            writer,
+            kind.new_encrypto(secret_key, Some(request_salt)),
+            kind.max_payload_len(),
+        )
+    }
+
+    // CryptoWriter::with_encrypto creates a writer sealing by crypto, each chunk carries
+    // max_payload_len bytes at most.
+    pub fn with_encrypto(
+        writer: T,
+        crypto: Box<dyn Encrypto + Send>,
+        max_payload_len: usize,
+    ) -> CryptoWriter<T> {
         CryptoWriter {
             crypto,
             writer,
             inited: false,
+            max_payload_len,
+            buf: BytesMut::with_capacity(chunk_len(max_payload_len)),
+            coalesce: false,
+            plain: BytesMut::new(),
         }
     }
+
+    // with_coalesce makes the writer buffer small writes into one chunk until it's
+    // full or flushed, which saves the per chunk overhead of interactive traffic.
+    pub fn with_coalesce(mut self, coalesce: bool) -> CryptoWriter<T> {
+        self.coalesce = coalesce;
+        if coalesce {
+            self.plain = BytesMut::with_capacity(self.max_payload_len);
+        }
+        self
+    }
+
+    // salt returns the salt of stream.
+    pub fn salt(&self) -> &[u8] {
+        self.crypto.salt()
+    }
+
+    // init appends the salt to buf before the first chunk.
+    fn init(&mut self) {
+        if !self.inited {
+            self.crypto.encrypt_init(&mut self.buf);
+            self.inited = true;
+        }
+    }
+
+    // seal appends plaintext sealed as one chunk to buf.
+    fn seal(&mut self, plaintext: &[u8]) {
+        self.init();
+        self.crypto.encrypt(plaintext, &mut self.buf);
+    }
+
+    // seal_plain seals the coalesced plaintext.
+    fn seal_plain(&mut self) {
+        if self.plain.is_empty() {
+            return;
+        }
+        self.init();
+        self.crypto.encrypt(&self.plain, &mut self.buf);
+        self.plain.clear();
+    }
+
+    // poll_write_buf writes all sealed chunks.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.writer).poll_write(cx, &self.buf))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.buf.advance(n);
+        }
+        Ok(()).into()
+    }
 }
 
 impl<T> io::AsyncWrite for CryptoWriter<T>
@@ -47,27 +423,37 @@ where
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
         let this = Pin::into_inner(self);
+        ready!(this.poll_write_buf(cx))?;
 
-        if !this.inited {
-            let data = this.crypto.encrypt_init();
-            ready!(Pin::new(&mut this.writer).poll_write(cx, data))?;
-            this.crypto.reset();
-            this.inited = true;
+        if this.coalesce {
+            let n = usize::min(buf.len(), this.max_payload_len - this.plain.len());
+            this.plain.extend_from_slice(&buf[..n]);
+            if this.plain.len() == this.max_payload_len {
+                this.seal_plain();
+            }
+            return Ok(n).into();
         }
 
-        let data = this.crypto.encrypt(buf);
-        ready!(Pin::new(&mut this.writer).poll_write(cx, data))?;
-        this.crypto.reset();
-        Ok(buf.len()).into()
+        // payload longer than a chunk is left to the next write.
+        let n = usize::min(buf.len(), this.max_payload_len);
+        this.seal(&buf[..n]);
+        // the sealed chunk is owned by the writer now, what's left is written by
+        // the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Err(e).into();
+        }
+        Ok(n).into()
     }
 
-    #[inline]
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        Pin::new(&mut Pin::into_inner(self).writer).poll_flush(cx)
+        let this = Pin::into_inner(self);
+        this.seal_plain();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
     }
 
-    #[inline]
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
         Pin::new(&mut Pin::into_inner(self).writer).poll_shutdown(cx)
     }
 }
@@ -76,53 +462,115 @@ pub struct CryptoReader<T>
 where
     T: io::AsyncRead + std::marker::Unpin,
 {
-    crypto: Box<dyn interface::Decrypto + Send>,
+    crypto: Box<dyn Decrypto + Send>,
     reader: T,
-    buf: Vec<u8>,
-    size: usize,
-    pos: usize,
-    buf_need_ret: bool,
+    // buf holds the stream read but not yet opened, chunks are split off from it
+    // and opened in place.
+    buf: BytesMut,
+    // plain holds the payload of last chunk not yet read.
+    plain: BytesMut,
+    // replay_filter rejects the stream if its salt has been seen.
+    replay_filter: Option<Arc<ReplayFilter>>,
+    salt_checked: bool,
 }
 
-const MAX_BUF_LEN: usize = (1 << 16) - 1;
+// chunk_len returns the length of a sealed chunk carrying max_payload_len bytes, its
+// length and its payload each followed by a tag. Buffers start at that and only grow
+// for the salt and headers leading a stream.
+fn chunk_len(max_payload_len: usize) -> usize {
+    2 + max_payload_len + 2 * aead::TAG_SIZE
+}
 
 impl<T> CryptoReader<T>
 where
     T: io::AsyncRead + std::marker::Unpin,
 {
-    pub fn new(reader: T, secret_key: &[u8]) -> CryptoReader<T> {
-        let crypto: Box<dyn interface::Decrypto + Send> =
-            Box::new(aes::Aes128Gcm0Decrypto::new(secret_key));
+    // CryptoReader::new creates a reader of request stream.
+    pub fn new(reader: T, kind: CipherKind, secret_key: &[u8]) -> CryptoReader<T> {
+        Self::with_decrypto(
+            reader,
+            kind.new_decrypto(secret_key, None),
+            kind.max_payload_len(),
+        )
+    }
 
+    // CryptoReader::new_response creates a reader of the response to a request stream
+    // with request_salt.
+    pub fn new_response(
+        reader: T,
+        kind: CipherKind,
+        secret_key: &[u8],
+        request_salt: &[u8],
+    ) -> CryptoReader<T> {
+        Self::with_decrypto(
+            reader,
+            kind.new_decrypto(secret_key, Some(request_salt)),
+            kind.max_payload_len(),
+        )
+    }
+
+    // CryptoReader::with_decrypto creates a reader opening by crypto, each chunk carries
+    // max_payload_len bytes at most.
+    pub fn with_decrypto(
+        reader: T,
+        crypto: Box<dyn Decrypto + Send>,
+        max_payload_len: usize,
+    ) -> CryptoReader<T> {
         CryptoReader {
             crypto,
             reader,
-            buf: Vec::with_capacity(MAX_BUF_LEN),
-            size: 0,
-            pos: 0,
-            buf_need_ret: false,
+            buf: BytesMut::with_capacity(chunk_len(max_payload_len)),
+            plain: BytesMut::new(),
+            replay_filter: None,
+            salt_checked: false,
         }
     }
 
-    fn poll_read_exact(&mut self, cx: &mut Context<'_>, size: usize) -> Poll<io::Result<()>> {
-        assert_eq!(self.pos, 0);
-        assert_ne!(size, 0);
-        unsafe {
-            self.buf.set_len(size);
+    // with_replay_filter makes the reader check its salt against replay_filter.
+    pub fn with_replay_filter(mut self, replay_filter: Arc<ReplayFilter>) -> CryptoReader<T> {
+        self.replay_filter = Some(replay_filter);
+        self
+    }
+
+    // check_salt checks the salt once the first chunk after it is opened, so that probes
+    // failing to authenticate can't fill the replay filter with their salts.
+    fn check_salt(&mut self) -> io::Result<()> {
+        if self.salt_checked {
+            return Ok(());
         }
-        while self.size < size {
-            let last = self.size;
-            let mut read_buf = ReadBuf::new(&mut self.buf[self.size..]);
-            ready!(Pin::new(&mut self.reader).poll_read(cx, &mut read_buf)?);
-            self.size += read_buf.filled().len();
-            if self.size == 0 {
-                return Ok(()).into();
-            }
-            if self.size == last {
+        let salt = match self.crypto.salt() {
+            Some(salt) => salt,
+            None => return Ok(()),
+        };
+        self.salt_checked = true;
+        match &self.replay_filter {
+            Some(filter) if !filter.check_and_insert(salt) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "repeated salt, probable replay attack",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // salt returns the salt of stream once it's read.
+    pub fn salt(&self) -> Option<&[u8]> {
+        self.crypto.salt()
+    }
+
+    // poll_fill_buf reads until buf holds size bytes at least, reading ahead as much
+    // as buf has room for. It returns false if the stream ends before a new chunk.
+    fn poll_fill_buf(&mut self, cx: &mut Context<'_>, size: usize) -> Poll<io::Result<bool>> {
+        while self.buf.len() < size {
+            self.buf.reserve(size - self.buf.len());
+            let n = ready!(poll_read_buf(Pin::new(&mut self.reader), cx, &mut self.buf))?;
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(false).into();
+                }
                 return Err(ErrorKind::UnexpectedEof.into()).into();
             }
         }
-        Ok(()).into()
+        Ok(true).into()
     }
 }
 
@@ -137,33 +585,30 @@ where
     ) -> Poll<io::Result<()>> {
         let this = Pin::into_inner(self);
 
-        if this.buf_need_ret {
-            if this.pos < this.size {
-                let len = usize::min(rbuf.remaining(), this.buf[this.pos..this.size].len());
-                rbuf.put_slice(&(this.buf[this.pos..this.pos + len]));
-                this.pos += len;
+        loop {
+            if !this.plain.is_empty() {
+                let len = usize::min(rbuf.remaining(), this.plain.len());
+                rbuf.put_slice(&this.plain[..len]);
+                this.plain.advance(len);
+                if this.plain.is_empty() {
+                    // release the chunk so that buf owns its memory again.
+                    this.plain = BytesMut::new();
+                }
                 return Ok(()).into();
             }
-            this.size = 0;
-            this.pos = 0;
-        }
 
-        loop {
             let size = this.crypto.next_size();
-            if size == 0 {
-                let len = usize::min(rbuf.remaining(), this.buf[this.pos..this.size].len());
-                rbuf.put_slice(&(this.buf[this.pos..this.pos + len]));
-                this.pos += len;
-                this.buf_need_ret = true;
+            if !ready!(this.poll_fill_buf(cx, size))? {
                 return Ok(()).into();
-            } else {
-                this.buf_need_ret = false;
-                ready!(this.poll_read_exact(cx, size)?);
-                if this.size == 0 {
-                    return Ok(()).into();
-                }
-                assert_eq!(size, this.size);
-                this.size = this.crypto.decrypt(&mut this.buf);
+            }
+            let mut chunk = this.buf.split_to(size);
+            let salt_read = this.crypto.salt().is_some();
+            this.crypto.decrypt(&mut chunk)?;
+            if salt_read {
+                this.check_salt()?;
+            }
+            if !chunk.is_empty() {
+                this.plain = chunk;
             }
         }
     }
@@ -188,9 +633,7 @@ pub fn evp_bytes_to_key(password: String, keylen: usize) -> Vec<u8> {
         md5.result(&mut ms[pos..]);
     }
 
-    unsafe {
-        ms.set_len(keylen);
-    }
+    ms.truncate(keylen);
     return ms;
 }
 
@@ -209,4 +652,288 @@ mod tests {
             ]
         );
     }
+
+    const METHODS: [&str; 6] = [
+        "aes-128-gcm",
+        "aes-256-gcm",
+        "chacha20-ietf-poly1305",
+        "2022-blake3-aes-128-gcm",
+        "2022-blake3-aes-256-gcm",
+        "2022-blake3-chacha20-poly1305",
+    ];
+
+    fn test_key(kind: CipherKind, password: &str) -> Vec<u8> {
+        if kind.is_2022() {
+            let key = evp_bytes_to_key(password.to_string(), kind.key_len());
+            return kind.derive_key(&base64::encode(key)).unwrap();
+        }
+        kind.derive_key(password).unwrap()
+    }
+
+    #[test]
+    fn test_registry() {
+        for info in CIPHERS.iter() {
+            let kind: CipherKind = info.method.parse().unwrap();
+            assert_eq!(kind.info(), info);
+            assert_eq!(kind.to_string(), info.method);
+
+            let key = test_key(kind, "foobar");
+            assert_eq!(key.len(), kind.key_len());
+            assert_eq!(kind.new_encrypto(&key, None).salt().len(), kind.salt_len());
+        }
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let kind = CipherKind::Blake3Aes128Gcm;
+        let key = kind.derive_key("AAECAwQFBgcICQoLDA0ODw==").unwrap();
+        assert_eq!(key, (0u8..16).collect::<Vec<u8>>());
+        assert!(kind.derive_key("foobar!").is_err());
+        assert!(CipherKind::Blake3Aes256Gcm
+            .derive_key("AAECAwQFBgcICQoLDA0ODw==")
+            .is_err());
+    }
+
+    #[test]
+    fn test_packet() {
+        for method in METHODS {
+            let kind = method.parse::<CipherKind>().unwrap();
+            let key = test_key(kind, "foobar");
+            let client = UdpSession::new();
+            let packet =
+                encrypt_packet(kind, &key, &client, b"\x01\x7f\x00\x00\x01\x00\x35hello").unwrap();
+            let (plaintext, header) = decrypt_packet(kind, &key, &packet, false).unwrap();
+            assert_eq!(plaintext, b"\x01\x7f\x00\x00\x01\x00\x35hello");
+
+            if let Some(header) = header {
+                assert_eq!(header.session_id, client.id());
+                assert_eq!(header.client_session_id, None);
+                // a request reflected back to the client is rejected.
+                assert!(decrypt_packet(kind, &key, &packet, true).is_err());
+
+                let server = UdpSession::new_server(header.session_id);
+                let packet = encrypt_packet(kind, &key, &server, b"world").unwrap();
+                let (plaintext, header) = decrypt_packet(kind, &key, &packet, true).unwrap();
+                assert_eq!(plaintext, b"world");
+                assert_eq!(header.unwrap().client_session_id, Some(client.id()));
+                assert!(decrypt_packet(kind, &key, &packet, false).is_err());
+            }
+
+            let other = test_key(kind, "barfoo");
+            assert!(decrypt_packet(kind, &other, &packet, false).is_err());
+            assert!(decrypt_packet(kind, &key, &packet[..20], false).is_err());
+        }
+        assert!("aes-256-cfb".parse::<CipherKind>().is_err());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.check(0));
+        assert!(!window.check(0));
+        assert!(window.check(5));
+        // ids within the window may arrive out of order, but only once.
+        assert!(window.check(3));
+        assert!(!window.check(3));
+        assert!(!window.check(5));
+
+        assert!(window.check(1028));
+        assert!(!window.check(4));
+        assert!(window.check(6));
+        assert!(!window.check(6));
+
+        // a jump past the window forgets every id.
+        assert!(window.check(5000));
+        assert!(!window.check(1028));
+        assert!(window.check(4999));
+        assert!(!window.check(4999));
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let addr = [0x01, 0x7f, 0x00, 0x00, 0x01, 0x00, 0x50];
+        for method in METHODS {
+            let kind = method.parse::<CipherKind>().unwrap();
+            let key = test_key(kind, "foobar");
+            let (client, server) = tokio::io::duplex(4096);
+            let (client_reader, client_writer) = tokio::io::split(client);
+            let (server_reader, server_writer) = tokio::io::split(server);
+
+            let mut writer = CryptoWriter::new(client_writer, kind, &key);
+            let mut reader = CryptoReader::new(server_reader, kind, &key);
+            writer.write_all(&addr).await.unwrap();
+            writer.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 12];
+            reader.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..7], &addr);
+            assert_eq!(&buf[7..], b"hello");
+
+            let request_salt = reader.salt().unwrap().to_vec();
+            assert_eq!(request_salt, writer.salt());
+            let mut writer = CryptoWriter::new_response(server_writer, kind, &key, &request_salt);
+            let mut reader = CryptoReader::new_response(client_reader, kind, &key, &request_salt);
+            writer.write_all(b"hello ").await.unwrap();
+            writer.write_all(b"world").await.unwrap();
+            let mut buf = [0u8; 11];
+            reader.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello world");
+        }
+    }
+
+    #[test]
+    fn test_buffer_len() {
+        for method in METHODS {
+            let kind = method.parse::<CipherKind>().unwrap();
+            let key = test_key(kind, "foobar");
+            let len = 2 + kind.max_payload_len() + 2 * kind.tag_len();
+            let writer = CryptoWriter::new(tokio::io::sink(), kind, &key);
+            assert_eq!(writer.buf.capacity(), len);
+            assert_eq!(writer.plain.capacity(), 0);
+            let reader = CryptoReader::new(tokio::io::empty(), kind, &key);
+            assert_eq!(reader.buf.capacity(), len);
+        }
+        // legacy chunks carry 0x3FFF bytes at most.
+        assert_eq!(
+            chunk_len(CipherKind::Aes128Gcm.max_payload_len()),
+            0x3FFF + 34
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_auth_failure() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        for method in METHODS {
+            let kind = method.parse::<CipherKind>().unwrap();
+            let (client, server) = tokio::io::duplex(4096);
+            let mut writer = CryptoWriter::new(client, kind, &test_key(kind, "foobar"));
+            let mut reader = CryptoReader::new(server, kind, &test_key(kind, "barfoo"));
+
+            writer
+                .write_all(&[0x01, 0x7f, 0x00, 0x00, 0x01, 0x00, 0x50])
+                .await
+                .unwrap();
+            let mut buf = [0u8; 7];
+            let err = reader.read_exact(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_replay() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        for method in METHODS {
+            let kind = method.parse::<CipherKind>().unwrap();
+            let key = test_key(kind, "foobar");
+            let (client, mut server) = tokio::io::duplex(4096);
+            let mut writer = CryptoWriter::new(client, kind, &key);
+            writer
+                .write_all(&[0x01, 0x7f, 0x00, 0x00, 0x01, 0x00, 0x50])
+                .await
+                .unwrap();
+            writer.flush().await.unwrap();
+            drop(writer);
+            let mut stream = Vec::new();
+            server.read_to_end(&mut stream).await.unwrap();
+
+            let filter = Arc::new(ReplayFilter::new(100, 1e-6).unwrap());
+            let read = |data: Vec<u8>| {
+                let mut reader = CryptoReader::new(std::io::Cursor::new(data), kind, &key)
+                    .with_replay_filter(filter.clone());
+                async move {
+                    let mut buf = [0u8; 7];
+                    reader.read_exact(&mut buf).await.map(|_| buf)
+                }
+            };
+
+            // a probe reusing the salt fails to authenticate and leaves no trace.
+            let mut probe = stream.clone();
+            probe[kind.salt_len()] ^= 0xff;
+            let err = read(probe).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            assert_eq!(read(stream.clone()).await.unwrap()[0], 0x01);
+            let err = read(stream).await.unwrap_err();
+            assert_eq!(err.to_string(), "repeated salt, probable replay attack");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_large_write() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        for method in METHODS {
+            let kind = method.parse::<CipherKind>().unwrap();
+            let key = test_key(kind, "foobar");
+            let (client, server) = tokio::io::duplex(4096);
+            let mut writer = CryptoWriter::new(client, kind, &key);
+            let mut reader = CryptoReader::new(server, kind, &key);
+
+            let mut data = vec![0x01, 0x7f, 0x00, 0x00, 0x01, 0x00, 0x50];
+            data.extend((0..200_000).map(|i| i as u8));
+            let expected = data.clone();
+            tokio::spawn(async move {
+                writer.write_all(&data).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_coalesce() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let kind = CipherKind::Aes128Gcm;
+        let key = test_key(kind, "foobar");
+        for coalesce in [false, true] {
+            let (client, mut server) = tokio::io::duplex(1 << 16);
+            let mut writer = CryptoWriter::new(client, kind, &key).with_coalesce(coalesce);
+            for _ in 0..100 {
+                writer.write_all(b"0123456789").await.unwrap();
+            }
+            writer.flush().await.unwrap();
+            drop(writer);
+
+            let mut raw = Vec::new();
+            server.read_to_end(&mut raw).await.unwrap();
+            let chunks = if coalesce { 1 } else { 100 };
+            assert_eq!(raw.len(), 16 + chunks * (2 + 16 + 16) + 1000);
+
+            let mut reader = CryptoReader::new(&raw[..], kind, &key);
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"0123456789".repeat(100));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_truncated() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let kind = CipherKind::Chacha20IetfPoly1305;
+        let key = test_key(kind, "foobar");
+        let mut raw = Vec::new();
+        let mut writer = CryptoWriter::new(&mut raw, kind, &key);
+        writer.write_all(b"hello").await.unwrap();
+        writer.write_all(b"world").await.unwrap();
+        drop(writer);
+
+        // a stream ending between chunks ends the plaintext.
+        let chunk_len = 2 + 16 + 5 + 16;
+        let mut reader = CryptoReader::new(&raw[..32 + chunk_len], kind, &key);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+
+        let mut reader = CryptoReader::new(&raw[..raw.len() - 1], kind, &key);
+        let mut buf = Vec::new();
+        let err = reader.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
 }
@@ -0,0 +1,683 @@
+// Package http implements http proxy protocol.
+use std::sync::Arc;
+use std::time::Duration;
+
+use bstr::ByteSlice;
+use log::{debug, error, info};
+use tokio::io;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use tokio::net::TcpStream;
+use tokio::time;
+use url::Url;
+
+use crate::address;
+use crate::config::{Local, Policy};
+use crate::socks::acl;
+use crate::socks::server;
+
+const MAX_HEAD_LEN: usize = 8192;
+
+// After the response to a forwarded request, what the client still sends is discarded
+// for this long before closing, so that the close doesn't reset the response.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Reader = Box<dyn AsyncRead + Unpin + Send>;
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+// Request is the head of a http proxy request.
+struct Request {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// rewrite_head returns the origin-form head of req to send to the origin of url,
+// without hop-by-hop headers, closing the connection after the response.
+fn rewrite_head(req: &Request, url: &Url) -> String {
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path += "?";
+        path += query;
+    }
+    let mut head = format!("{} {} {}\r\n", req.method, path, req.version);
+    if req.header("Host").is_none() {
+        head += &format!("Host: {}\r\n", target_host(url));
+    }
+    // headers listed in Connection are hop-by-hop as well.
+    let listed: Vec<&str> = req
+        .headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|h| h.trim())
+        .collect();
+    for (k, v) in req.headers.iter() {
+        if is_hop_by_hop(k) || listed.iter().any(|h| h.eq_ignore_ascii_case(k)) {
+            continue;
+        }
+        head += &format!("{}: {}\r\n", k, v);
+    }
+    head += "Connection: close\r\n\r\n";
+    head
+}
+
+// target_host returns the Host header value for an absolute-form target.
+fn target_host(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+// HTTPRelay as a http proxy server and mika client.
+pub struct HTTPRelay {
+    acl_manager: Arc<acl::ACLManager>,
+    server_manager: Arc<server::ServerManager>,
+    local: Arc<Local>,
+    // user authenticated by Proxy-Authorization, empty if no authentication is required.
+    user: String,
+}
+
+impl HTTPRelay {
+    // HTTPRelay::new creates a new HTTPRelay serving the local listener.
+    pub fn new(
+        acl_manager: Arc<acl::ACLManager>,
+        server_manager: Arc<server::ServerManager>,
+        local: Arc<Local>,
+    ) -> HTTPRelay {
+        HTTPRelay {
+            acl_manager,
+            server_manager,
+            local,
+            user: String::new(),
+        }
+    }
+
+    // serve handles connection between http proxy client and remote addr.
+    pub async fn serve(mut self, mut conn: TcpStream) -> io::Result<()> {
+        let (head, rest) = read_head(&mut conn).await?;
+        let req = match parse_request(&head) {
+            Ok(req) => req,
+            Err(e) => {
+                write_status(&mut conn, "400 Bad Request").await?;
+                return Err(e);
+            }
+        };
+        debug!("http {} {}", req.method, req.target);
+
+        if !self.authenticate(&req) {
+            conn.write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                Proxy-Authenticate: Basic realm=\"mika\"\r\n\
+                Content-Length: 0\r\n\r\n",
+            )
+            .await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "http proxy authentication failed",
+            ));
+        }
+
+        if req.method.eq_ignore_ascii_case("CONNECT") {
+            self.connect(conn, req, rest).await
+        } else {
+            self.forward(conn, req, rest).await
+        }
+    }
+
+    // authenticate checks the Basic Proxy-Authorization against the users of local listener.
+    fn authenticate(&mut self, req: &Request) -> bool {
+        if self.local.users.is_empty() {
+            return true;
+        }
+
+        // the scheme is compared as bytes, the value may not be split at a char boundary.
+        let credentials = match req.header("Proxy-Authorization") {
+            Some(v)
+                if v.as_bytes()
+                    .get(..6)
+                    .is_some_and(|p| p.eq_ignore_ascii_case(b"basic ")) =>
+            {
+                v[6..].trim()
+            }
+            _ => return false,
+        };
+        let decoded = match base64::decode(credentials) {
+            Ok(d) => String::from_utf8_lossy(&d).to_string(),
+            Err(_) => return false,
+        };
+        let (username, password) = match decoded.split_once(':') {
+            Some(pair) => pair,
+            None => return false,
+        };
+
+        if self
+            .local
+            .users
+            .iter()
+            .any(|u| u.verify(username, password.as_bytes()))
+        {
+            self.user = username.to_string();
+            return true;
+        }
+        false
+    }
+
+    // connect handles CONNECT method by tunnelling the connection.
+    async fn connect(self, mut conn: TcpStream, req: Request, rest: Vec<u8>) -> io::Result<()> {
+        let addr = match parse_authority(&req.target) {
+            Ok(addr) => addr,
+            Err(e) => {
+                write_status(&mut conn, "400 Bad Request").await?;
+                return Err(e);
+            }
+        };
+
+        let (server_reader, mut server_writer) = match self.dial(addr).await {
+            Ok(server) => server,
+            Err(e) => {
+                write_status(&mut conn, status_line(&e)).await?;
+                return Err(e);
+            }
+        };
+        conn.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+
+        if !rest.is_empty() {
+            server_writer.write_all(&rest).await?;
+        }
+        relay(conn, server_reader, server_writer).await;
+        Ok(())
+    }
+
+    // forward handles requests in absolute-form by rewriting them to origin-form.
+    // Only one request is read from the client, the connection is closed after its
+    // response since later requests may be bound to other hosts.
+    async fn forward(self, mut conn: TcpStream, req: Request, rest: Vec<u8>) -> io::Result<()> {
+        let url = match Url::parse(&req.target) {
+            Ok(url) if url.scheme() == "http" && url.host_str().is_some_and(|h| h.len() <= 255) => {
+                url
+            }
+            _ => {
+                write_status(&mut conn, "400 Bad Request").await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported request target {}", req.target),
+                ));
+            }
+        };
+        let host = url.host_str().unwrap_or_default().to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addr = address::get_address_from_url(host, port)?;
+        let head = rewrite_head(&req, &url);
+
+        let (mut server_reader, mut server_writer) = match self.dial(addr).await {
+            Ok(server) => server,
+            Err(e) => {
+                write_status(&mut conn, status_line(&e)).await?;
+                return Err(e);
+            }
+        };
+        server_writer.write_all(head.as_bytes()).await?;
+        let (cr, mut cw) = conn.into_split();
+        let mut client = io::BufReader::new(std::io::Cursor::new(rest).chain(cr));
+        copy_body(&req, &mut client, &mut server_writer).await?;
+        server_writer.flush().await?;
+
+        // the writer is kept until the response ends, some origins stop on half-close.
+        let res = io::copy(&mut server_reader, &mut cw).await;
+        drop(server_writer);
+        cw.shutdown().await?;
+        let mut discard = [0u8; 1024];
+        let _ = time::timeout(LINGER_TIMEOUT, async {
+            while let Ok(n) = client.read(&mut discard).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        })
+        .await;
+        res.map(|_| ())
+    }
+
+    // dial connects to addr according to its ACL.
+    async fn dial(&self, addr: address::Address) -> io::Result<(Reader, Writer)> {
+        match self.acl_manager.acl(&addr, &self.user) {
+            Policy::Direct => {
+                info!("directly connect to {} for {}", &addr, self.user_name());
+                let server = addr.new_conn().await?;
+                let (rr, rw) = server.into_split();
+                Ok((Box::new(rr), Box::new(rw)))
+            }
+            Policy::Reject => {
+                info!("reject {} for {}", &addr, self.user_name());
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("connection to {} not allowed", &addr),
+                ))
+            }
+            Policy::Proxy => self.dial_by_proxy(addr, None).await,
+            Policy::ProxyGroup(pg) => self.dial_by_proxy(addr, Some(pg)).await,
+        }
+    }
+
+    // dial_by_proxy connects to addr via mika server.
+    async fn dial_by_proxy(
+        &self,
+        addr: address::Address,
+        pg: Option<String>,
+    ) -> io::Result<(Reader, Writer)> {
+        let (mut server_writer, server_reader, remote_addr, _) =
+            self.server_manager.pick_one(pg, &addr).await?;
+        server_writer
+            .write_all(&address::address_to_vec(&addr))
+            .await?;
+        info!(
+            "connect to {} via {} for {}",
+            &addr,
+            remote_addr,
+            self.user_name()
+        );
+        Ok((server_reader, server_writer))
+    }
+
+    // user_name returns the authenticated user for logging.
+    fn user_name(&self) -> &str {
+        if self.user.is_empty() {
+            "anonymous"
+        } else {
+            &self.user
+        }
+    }
+}
+
+// read_head reads the request head, returning it and the bytes read past it.
+async fn read_head(conn: &mut TcpStream) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(idx) = buf.find("\r\n\r\n") {
+            let rest = buf.split_off(idx + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "http request head too large",
+            ));
+        }
+    }
+}
+
+// parse_request parses request line and headers of head.
+fn parse_request(head: &[u8]) -> io::Result<Request> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid http request");
+
+    let head = head.to_str().map_err(|_| invalid())?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().ok_or_else(invalid)?.split(' ');
+    let method = request_line.next().ok_or_else(invalid)?.to_string();
+    let target = request_line.next().ok_or_else(invalid)?.to_string();
+    let version = request_line.next().ok_or_else(invalid)?.to_string();
+    if !version.starts_with("HTTP/") {
+        return Err(invalid());
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (k, v) = line.split_once(':').ok_or_else(invalid)?;
+        headers.push((k.trim().to_string(), v.trim().to_string()));
+    }
+
+    Ok(Request {
+        method,
+        target,
+        version,
+        headers,
+    })
+}
+
+// parse_authority parses the host:port target of CONNECT method.
+fn parse_authority(target: &str) -> io::Result<address::Address> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid authority {}", target),
+        )
+    };
+
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    if host.is_empty() || host.len() > 255 || Url::parse(&format!("https://{}", host)).is_err() {
+        return Err(invalid());
+    }
+    address::get_address_from_url(host.to_string(), port)
+}
+
+// copy_body copies the body of req from client to server, as delimited by its
+// Content-Length or chunked Transfer-Encoding, nothing past it is read.
+async fn copy_body<R, W>(req: &Request, client: &mut R, server: &mut W) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let chunked = req
+        .header("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().trim_end().ends_with("chunked"));
+    if !chunked {
+        let len: u64 = match req.header("Content-Length") {
+            Some(len) => len.parse().map_err(|_| invalid("invalid content length"))?,
+            None => return Ok(()),
+        };
+        let n = io::copy(&mut client.take(len), server).await?;
+        if n < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(());
+    }
+
+    // each chunk is its size line, its data and CRLF, the last one is empty and is
+    // followed by trailers up to an empty line.
+    loop {
+        let line = read_line(client).await?;
+        server.write_all(line.as_bytes()).await?;
+        let size = line.trim_end().split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        let n = io::copy(&mut client.take(size + 2), server).await?;
+        if n < size + 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    loop {
+        let line = read_line(client).await?;
+        server.write_all(line.as_bytes()).await?;
+        if line == "\r\n" {
+            return Ok(());
+        }
+    }
+}
+
+// read_line reads a line of a chunked body up to its CRLF.
+async fn read_line<R: AsyncBufRead + Unpin>(client: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    let n = client
+        .take(MAX_HEAD_LEN as u64)
+        .read_line(&mut line)
+        .await?;
+    if n == 0 || !line.ends_with("\r\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid chunked body",
+        ));
+    }
+    Ok(line)
+}
+
+fn is_hop_by_hop(header: &str) -> bool {
+    [
+        "Proxy-Connection",
+        "Proxy-Authorization",
+        "Connection",
+        "Keep-Alive",
+        "TE",
+        "Trailer",
+        "Upgrade",
+    ]
+    .iter()
+    .any(|h| h.eq_ignore_ascii_case(header))
+}
+
+fn status_line(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => "403 Forbidden",
+        io::ErrorKind::TimedOut => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    }
+}
+
+async fn write_status(conn: &mut TcpStream, status: &str) -> io::Result<()> {
+    let resp = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+    conn.write_all(resp.as_bytes()).await
+}
+
+// relay copies data between the http proxy client and remote connection.
+async fn relay(conn: TcpStream, mut server_reader: Reader, mut server_writer: Writer) {
+    if let Err(e) = server_writer.flush().await {
+        error!("io flush failed {}", e);
+        return;
+    }
+    let (mut cr, mut cw) = conn.into_split();
+    tokio::spawn(async move {
+        if let Err(e) = io::copy(&mut server_reader, &mut cw).await {
+            error!("io remote copy failed {}", e);
+        }
+    });
+
+    if let Err(e) = io::copy(&mut cr, &mut server_writer).await {
+        error!("io client copy failed {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ACLConfig;
+    use crate::crypto::ReplayFilter;
+
+    fn request(head: &str) -> Request {
+        parse_request(head.as_bytes()).unwrap()
+    }
+
+    fn new_relay(local: &str) -> HTTPRelay {
+        let replay_filter = Arc::new(ReplayFilter::new(1000, 1e-6).unwrap());
+        HTTPRelay::new(
+            Arc::new(acl::ACLManager::new(ACLConfig {
+                rules: vec![],
+                fnl: Policy::Direct,
+            })),
+            Arc::new(server::ServerManager::new(vec![], vec![], replay_filter).unwrap()),
+            Arc::new(serde_yaml::from_str(local).unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let req = request("GET http://a.com/x?y=1 HTTP/1.1\r\nHost: a.com\r\nX-A:  b \r\n\r\n");
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.target, "http://a.com/x?y=1");
+        assert_eq!(req.version, "HTTP/1.1");
+        assert_eq!(req.header("host"), Some("a.com"));
+        assert_eq!(req.header("x-a"), Some("b"));
+        assert_eq!(req.header("X-B"), None);
+
+        assert!(parse_request(b"GET http://a.com/\r\n\r\n").is_err());
+        assert!(parse_request(b"GET http://a.com/ FTP/1.0\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nno colon\r\n\r\n").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\nX: \xff\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        assert_eq!(
+            parse_authority("a.com:8443").unwrap().to_string(),
+            "a.com:8443"
+        );
+        assert!(parse_authority("a.com:443").unwrap().is_domain());
+        assert_eq!(
+            parse_authority("1.2.3.4:80").unwrap().to_string(),
+            "1.2.3.4:80"
+        );
+        assert_eq!(
+            parse_authority("[::1]:8080").unwrap().to_string(),
+            "[::1]:8080"
+        );
+        for target in [
+            "a.com",
+            "a.com:",
+            "a.com:http",
+            ":443",
+            "a.com:65536",
+            "a b:443",
+        ] {
+            assert!(parse_authority(target).is_err(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_head() {
+        let req = request(
+            "POST http://a.com:8080/x?y=1 HTTP/1.1\r\n\
+            Proxy-Authorization: Basic YTpi\r\n\
+            Proxy-Connection: keep-alive\r\n\
+            Connection: keep-alive, X-Hop\r\n\
+            Keep-Alive: timeout=5\r\n\
+            X-Hop: 1\r\n\
+            Upgrade: websocket\r\n\
+            Content-Length: 2\r\n\r\n",
+        );
+        let url = Url::parse(&req.target).unwrap();
+        assert_eq!(
+            rewrite_head(&req, &url),
+            "POST /x?y=1 HTTP/1.1\r\nHost: a.com:8080\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+        );
+
+        let req = request("GET http://a.com/ HTTP/1.0\r\nHost: b.com\r\n\r\n");
+        let url = Url::parse(&req.target).unwrap();
+        assert_eq!(
+            rewrite_head(&req, &url),
+            "GET / HTTP/1.0\r\nHost: b.com\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    // body copies the body of the request of head from client, and returns it and the
+    // bytes of client left unread.
+    async fn body(head: &str, client: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut client = io::BufReader::new(client);
+        let mut server = Vec::new();
+        copy_body(&request(head), &mut client, &mut server).await?;
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        Ok((server, rest))
+    }
+
+    #[tokio::test]
+    async fn test_copy_body() {
+        let next = "GET http://b.com/ HTTP/1.1\r\n\r\n";
+        let (sent, rest) = body("GET / HTTP/1.1\r\n\r\n", next.as_bytes())
+            .await
+            .unwrap();
+        assert!(sent.is_empty());
+        assert_eq!(rest, next.as_bytes());
+
+        let (sent, rest) = body(
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n",
+            format!("hello{}", next).as_bytes(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sent, b"hello");
+        assert_eq!(rest, next.as_bytes());
+
+        let chunked = "5;ext=1\r\nhello\r\n1\r\n!\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let (sent, rest) = body(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\nContent-Length: 1\r\n\r\n",
+            format!("{}{}", chunked, next).as_bytes(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sent, chunked.as_bytes());
+        assert_eq!(rest, next.as_bytes());
+
+        let cl = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        assert!(body(cl, b"hell").await.is_err());
+        assert!(body("POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n", b"")
+            .await
+            .is_err());
+        let te = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(body(te, b"z\r\n").await.is_err());
+        assert!(body(te, b"5\r\nhel").await.is_err());
+        assert!(body(te, b"0\r\n").await.is_err());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = "{address: 127.0.0.1, port: 0, users: [{username: alice, password: 's:ecret'}]}";
+        let with = |authorization: &str| {
+            request(&format!(
+                "GET http://a.com/ HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n",
+                authorization
+            ))
+        };
+
+        let mut open = new_relay("{address: 127.0.0.1, port: 0}");
+        assert!(open.authenticate(&request("GET http://a.com/ HTTP/1.1\r\n\r\n")));
+        assert_eq!(open.user, "");
+
+        let mut relay = new_relay(auth);
+        assert!(!relay.authenticate(&request("GET http://a.com/ HTTP/1.1\r\n\r\n")));
+        for bad in [
+            format!("Basic {}", base64::encode("alice:secret")),
+            format!("Basic {}", base64::encode("bob:s:ecret")),
+            format!("Basic {}", base64::encode("alice")),
+            format!("Bearer {}", base64::encode("alice:s:ecret")),
+            "Basic !!!".to_string(),
+            "Basé".to_string(),
+            "aéééé".to_string(),
+        ] {
+            assert!(!relay.authenticate(&with(&bad)), "{}", bad);
+            assert_eq!(relay.user, "");
+        }
+        // the password is what follows the first colon.
+        let good = format!("basic  {}", base64::encode("alice:s:ecret"));
+        assert!(relay.authenticate(&with(&good)));
+        assert_eq!(relay.user, "alice");
+    }
+
+    #[tokio::test]
+    async fn test_serve_unauthenticated() {
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = new_relay(
+            "{address: 127.0.0.1, port: 0, users: [{username: alice, password: secret}]}",
+        );
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            relay.serve(conn).await
+        });
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(
+            "GET http://a.com/ HTTP/1.1\r\nProxy-Authorization: aéééé\r\n\r\n".as_bytes(),
+        )
+        .await
+        .unwrap();
+        let mut resp = Vec::new();
+        conn.read_to_end(&mut resp).await.unwrap();
+        assert!(resp.starts_with(b"HTTP/1.1 407 "));
+        let err = server.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
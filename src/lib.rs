@@ -1,7 +1,10 @@
 pub mod address;
 pub mod config;
 pub mod crypto;
+pub mod http;
+pub mod local;
 pub mod manager;
 pub mod mika;
 pub mod obfs;
 pub mod socks;
+pub mod transport;
@@ -0,0 +1,217 @@
+// Package local serves the local listeners of mika client, each with its own protocol
+// and acl.
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{ACLConfig, Local, Protocol};
+use crate::http::HTTPRelay;
+use crate::socks;
+use crate::socks::acl::ACLManager;
+use crate::socks::server::ServerManager;
+use crate::socks::TCPRelay;
+
+// acl_config takes the acl of local, or global if it has none, with the final policy
+// of local if it has one.
+pub fn acl_config(local: &mut Local, global: &ACLConfig) -> ACLConfig {
+    let mut acl_cfg = local.acl.take().unwrap_or_else(|| global.clone());
+    if let Some(fnl) = local.fnl.clone() {
+        acl_cfg.fnl = fnl;
+    }
+    acl_cfg
+}
+
+// serve accepts connections of one local listener.
+pub async fn serve(
+    listen: TcpListener,
+    server_manager: Arc<ServerManager>,
+    acl_manager: Arc<ACLManager>,
+    local: Arc<Local>,
+) {
+    loop {
+        let (stream, _) = match listen.accept().await {
+            Ok(a) => a,
+            Err(err) => {
+                error!("{}", err);
+                return;
+            }
+        };
+        let sk = server_manager.clone();
+        let acl_manager = acl_manager.clone();
+        let local = local.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, sk, acl_manager, local).await {
+                debug!("relay failed {}", e);
+            }
+        });
+    }
+}
+
+// handle relays one connection by the protocol of local. Mixed listeners tell socks4/4a,
+// socks5 and http apart by the first byte.
+pub async fn handle(
+    stream: TcpStream,
+    server_manager: Arc<ServerManager>,
+    acl_manager: Arc<ACLManager>,
+    local: Arc<Local>,
+) -> io::Result<()> {
+    match local.protocol {
+        Protocol::Socks5 => {
+            let socks5s = TCPRelay::new(acl_manager, server_manager, local);
+            socks5s.serve(stream).await
+        }
+        Protocol::Http => {
+            let https = HTTPRelay::new(acl_manager, server_manager, local);
+            https.serve(stream).await
+        }
+        Protocol::Mixed => {
+            let mut ver = [0u8; 1];
+            if stream.peek(&mut ver).await? == 0 {
+                return Ok(());
+            }
+            match ver[0] {
+                socks::SOCKS_V5 => {
+                    let socks5s = TCPRelay::new(acl_manager, server_manager, local);
+                    socks5s.serve(stream).await
+                }
+                socks::SOCKS_V4 => {
+                    let socks4s = TCPRelay::new(acl_manager, server_manager, local);
+                    socks4s.serve_v4(stream).await
+                }
+                _ => {
+                    let https = HTTPRelay::new(acl_manager, server_manager, local);
+                    https.serve(stream).await
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Policy;
+    use crate::crypto::ReplayFilter;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_acl_config() {
+        let global: ACLConfig = serde_yaml::from_str("{rules: [], final: Proxy}").unwrap();
+        let mut local: Local = serde_yaml::from_str("{address: 127.0.0.1, port: 0}").unwrap();
+        assert_eq!(acl_config(&mut local, &global), global);
+
+        let mut local: Local =
+            serde_yaml::from_str("{address: 127.0.0.1, port: 0, final: Reject}").unwrap();
+        assert_eq!(acl_config(&mut local, &global).fnl, Policy::Reject);
+
+        let mut local: Local = serde_yaml::from_str(
+            "{address: 127.0.0.1, port: 0, acl: {rules: [{pattern: [a.com], mode: Domain, \
+             policy: Direct}], final: Reject}, final: Direct}",
+        )
+        .unwrap();
+        let acl_cfg = acl_config(&mut local, &global);
+        assert_eq!(acl_cfg.rules.len(), 1);
+        assert_eq!(acl_cfg.fnl, Policy::Direct);
+        assert!(local.acl.is_none());
+    }
+
+    // start serves a local listener described by yaml with the global acl of Direct.
+    async fn start(yaml: &str) -> SocketAddr {
+        let global = ACLConfig {
+            rules: vec![],
+            fnl: Policy::Direct,
+        };
+        let mut local: Local = serde_yaml::from_str(yaml).unwrap();
+        let acl_manager = Arc::new(ACLManager::new(acl_config(&mut local, &global)));
+        let replay_filter = Arc::new(ReplayFilter::new(1000, 1e-6).unwrap());
+        let server_manager = Arc::new(ServerManager::new(vec![], vec![], replay_filter).unwrap());
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listen.local_addr().unwrap();
+        tokio::spawn(serve(listen, server_manager, acl_manager, Arc::new(local)));
+        addr
+    }
+
+    // echo_target starts a target echoing what it reads.
+    async fn echo_target() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn assert_echo(stream: &mut TcpStream) {
+        let mut buf = [0u8; 4];
+        stream.write_all(b"ping").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    // socks5_connect sends a socks5 CONNECT to target and returns the reply code.
+    async fn socks5_connect(local: SocketAddr, target: SocketAddr) -> (TcpStream, u8) {
+        let mut stream = TcpStream::connect(local).await.unwrap();
+        let mut request = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&target.port().to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 12];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [5, 0]);
+        (stream, reply[3])
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let target = echo_target().await;
+        let socks5_local = start("{address: 127.0.0.1, port: 0, final: Reject}").await;
+        let mixed_local = start("{address: 127.0.0.1, port: 0, protocol: mixed}").await;
+
+        // each local applies its own final policy.
+        let (_, rep) = socks5_connect(socks5_local, target).await;
+        assert_eq!(rep, 0x02);
+        let (mut stream, rep) = socks5_connect(mixed_local, target).await;
+        assert_eq!(rep, 0x00);
+        assert_echo(&mut stream).await;
+
+        // a socks5 local doesn't speak http.
+        let mut stream = TcpStream::connect(socks5_local).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes())
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply).await;
+        assert!(!reply.starts_with(b"HTTP/1.1"));
+
+        // a mixed local tells socks4 and http by the first byte.
+        let mut stream = TcpStream::connect(mixed_local).await.unwrap();
+        let mut request = vec![4, 1];
+        request.extend_from_slice(&target.port().to_be_bytes());
+        request.extend_from_slice(&[127, 0, 0, 1, 0]);
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 8];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0, 0x5A]);
+        assert_echo(&mut stream).await;
+
+        let mut stream = TcpStream::connect(mixed_local).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes())
+            .await
+            .unwrap();
+        let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        let mut reply = vec![0u8; established.len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, established);
+        assert_echo(&mut stream).await;
+    }
+}
@@ -3,10 +3,11 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use serde_json;
 use std::convert::Infallible;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use crate::socks::server::{ProxyGroupStatePatch, ServerManager};
+use crate::socks::server::{ProxyGroupSelection, ProxyGroupStatePatch, ServerManager};
 
 pub struct HTTPManager {
     server_manager: Arc<ServerManager>,
@@ -60,10 +61,32 @@ impl HTTPManager {
         Ok(Response::new(data.into()))
     }
 
+    async fn select_proxy(
+        self: Arc<Self>,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        let whole_body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let selection: ProxyGroupSelection = match serde_json::from_slice(whole_body.as_bytes()) {
+            Ok(selection) => selection,
+            Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, e.to_string())),
+        };
+        if let Err(e) = self.server_manager.select(&selection) {
+            let status = match e.kind() {
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            return Ok(error_response(status, e.to_string()));
+        }
+        let proxy_groups = self.server_manager.get_state();
+        let data = serde_json::to_string_pretty(&proxy_groups).unwrap();
+        Ok(Response::new(data.into()))
+    }
+
     async fn router(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
         match (req.method(), req.uri().path()) {
             (&Method::GET, "/") => self.get_current_state(req).await,
             (&Method::PUT, "/proxy_group") => self.update_proxy_groups(req).await,
+            (&Method::PUT, "/proxy_group/select") => self.select_proxy(req).await,
             // Return the 404 Not Found for other routes.
             _ => {
                 let mut not_found = Response::default();
@@ -73,3 +96,9 @@ impl HTTPManager {
         }
     }
 }
+
+fn error_response(status: StatusCode, msg: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(msg));
+    *response.status_mut() = status;
+    response
+}
@@ -1,11 +1,24 @@
 #![allow(dead_code)]
 
 // Package mika implements ss proxy protocol.
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info};
+use rand::Rng;
 use tokio::io;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::time;
 
 use crate::address;
-use crate::crypto::{CryptoReader, CryptoWriter};
+use crate::config::{ObfsMode, WebSocketConfig};
+use crate::crypto::{CipherKind, CryptoReader, CryptoWriter, ReplayFilter};
+use crate::obfs;
+use crate::transport::tls::TlsAcceptor;
+use crate::transport::{websocket, Stream};
+
+pub mod udp;
 
 const SOCKSV5: u8 = 0x05;
 const DEBUG: bool = false;
@@ -14,32 +27,149 @@ const CONNECT: u8 = 0x01;
 const BIND: u8 = 0x02;
 const UDP_ASSOCIATE: u8 = 0x03;
 
+// Connections failing the handshake are drained for a random delay in this range
+// before closing, so that active probes can't tell the server from other services.
+const PROBE_DRAIN_MIN: Duration = Duration::from_secs(4);
+const PROBE_DRAIN_MAX: Duration = Duration::from_secs(60);
+
 // TCPRelay as a socks5 server and mika client.
-pub struct TCPRelay {}
+#[derive(Clone)]
+pub struct TCPRelay {
+    // replay_filter rejects replayed request streams.
+    replay_filter: Arc<ReplayFilter>,
+    // coalesce buffers small writes of response stream until flush.
+    coalesce: bool,
+    // obfs is the host and mode of obfs handshake clients must send first.
+    obfs: Option<(String, ObfsMode)>,
+    // ws is the websocket transport clients connect through.
+    ws: Option<WebSocketConfig>,
+    // tls is the TLS server clients connect through, under ws.
+    tls: Option<TlsAcceptor>,
+}
 
 impl TCPRelay {
     // TCPRelay::new creates a new mika instance.
-    pub fn new() -> TCPRelay {
-        TCPRelay {}
+    pub fn new(replay_filter: Arc<ReplayFilter>, coalesce: bool) -> TCPRelay {
+        TCPRelay {
+            replay_filter,
+            coalesce,
+            obfs: None,
+            ws: None,
+            tls: None,
+        }
+    }
+
+    // with_obfs makes the relay accept the obfs handshake to host before the stream,
+    // as sent by clients with obfs_url.
+    pub fn with_obfs(mut self, host: String, mode: ObfsMode) -> TCPRelay {
+        self.obfs = Some((host, mode));
+        self
+    }
+
+    // with_websocket makes the relay accept the stream in websocket frames, as sent by
+    // clients with ws.
+    pub fn with_websocket(mut self, ws: WebSocketConfig) -> TCPRelay {
+        self.ws = Some(ws);
+        self
+    }
+
+    // with_tls makes the relay accept TLS before anything else, as sent by clients
+    // with tls.
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> TCPRelay {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    // accept_transport makes the handshakes of tls and ws if set, and splits conn.
+    async fn accept_transport(
+        &self,
+        conn: TcpStream,
+    ) -> io::Result<(
+        Box<dyn AsyncRead + Unpin + Send>,
+        Box<dyn AsyncWrite + Unpin + Send>,
+    )> {
+        if self.tls.is_none() && self.ws.is_none() {
+            let (cr, cw) = conn.into_split();
+            return Ok((Box::new(cr), Box::new(cw)));
+        }
+        let mut stream: Box<dyn Stream> = Box::new(conn);
+        if let Some(acceptor) = &self.tls {
+            stream = Box::new(acceptor.accept(stream).await?);
+        }
+        if let Some(ws) = &self.ws {
+            stream = Box::new(websocket::accept(stream, ws).await?);
+        }
+        let (cr, cw) = io::split(stream);
+        Ok((Box::new(cr), Box::new(cw)))
     }
 
     // serve handles connection between socks5 client and remote addr.
-    pub async fn serve(self, conn: TcpStream, secret_key: &Vec<u8>) {
-        let (mut cr, mut cw) = conn.into_split();
-        let mut client_reader = CryptoReader::new(&mut cr, &secret_key);
+    pub async fn serve(self, conn: TcpStream, cipher: CipherKind, secret_key: &Vec<u8>) {
+        let peer = conn.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        let (mut cr, mut writer) = match self.accept_transport(conn).await {
+            Ok(halves) => halves,
+            Err(e) => {
+                error!("transport handshake from {} failed {}", peer, e);
+                return;
+            }
+        };
+        let mut reader: Box<dyn AsyncRead + Unpin + Send + '_> = match &self.obfs {
+            None => Box::new(&mut cr),
+            Some((host, mode)) => {
+                let accepted = obfs::accept(&mut cr, &mut writer, host, *mode).await;
+                match accepted {
+                    Ok(r) => {
+                        writer = Box::new(obfs::ObfsWriter::new_response(writer, *mode));
+                        Box::new(r)
+                    }
+                    Err(e) => {
+                        error!("obfs handshake from {} failed {}", peer, e);
+                        drain(&mut cr, &peer).await;
+                        return;
+                    }
+                }
+            }
+        };
+        let mut client_reader = CryptoReader::new(&mut reader, cipher, &secret_key)
+            .with_replay_filter(self.replay_filter.clone());
 
         // get cmd and address
-        let addr = address::get_address(&mut client_reader).await.unwrap();
-        let remote = addr.new_conn().await.unwrap();
+        let addr = match address::get_address(&mut client_reader).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("read address from {} failed {}", peer, e);
+                drop(client_reader);
+                drain(&mut reader, &peer).await;
+                return;
+            }
+        };
+        let request_salt = client_reader.salt().unwrap_or_default().to_vec();
+        let target = addr.to_string();
+        let remote = match addr.new_conn().await {
+            Ok(remote) => remote,
+            Err(e) => {
+                error!("connect to {} for {} failed {}", target, peer, e);
+                return;
+            }
+        };
         let (mut rr, mut rw) = remote.into_split();
         let sk = secret_key.clone();
+        let coalesce = self.coalesce;
         tokio::spawn(async move {
-            let mut client_writer = CryptoWriter::new(&mut cw, &sk);
+            let mut client_writer = CryptoWriter::new_response(writer, cipher, &sk, &request_salt)
+                .with_coalesce(coalesce);
             io::copy(&mut rr, &mut client_writer).await
         });
         if let Err(e) = io::copy(&mut client_reader, &mut rw).await {
-            println!("io copy failed {}", e);
-            return;
+            error!("io copy from {} failed {}", peer, e);
         }
     }
 }
+
+// drain reads and discards data from a connection failing the handshake until it's
+// closed by peer or a random delay expires, the write half is kept open meanwhile.
+async fn drain<R: AsyncRead + Unpin>(reader: &mut R, peer: &str) {
+    let delay = rand::thread_rng().gen_range(PROBE_DRAIN_MIN..PROBE_DRAIN_MAX);
+    info!("drain {} for {:?} before closing", peer, delay);
+    let _ = time::timeout(delay, io::copy(reader, &mut io::sink())).await;
+}
@@ -0,0 +1,517 @@
+// Package udp implements the shadowsocks UDP relay of mika server.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+
+use crate::address;
+use crate::crypto;
+use crate::crypto::{CipherKind, PacketHeader, ReplayWindow, UdpSession};
+
+const MAX_UDP_PACKET_LEN: usize = 65536;
+
+// Datagrams queued for a session beyond this are dropped.
+const SESSION_QUEUE_LEN: usize = 64;
+
+// The resolved domains of a session are forgotten once there are that many.
+const MAX_RESOLVED: usize = 1024;
+
+// Replay windows are kept this long after their last datagram at least, longer than
+// datagram timestamps are accepted for.
+const REPLAY_RETENTION: Duration = Duration::from_secs(60);
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
+type ClientSessions = Arc<Mutex<HashMap<u64, ClientSession>>>;
+type Datagram = (address::Address, Vec<u8>);
+
+// Session is a NAT entry mapping one client to its outbound sockets. Its task resolves
+// targets and sends the datagrams queued, so that the receive loop never waits on them.
+struct Session {
+    queue: mpsc::Sender<Datagram>,
+    // client_session_id is the shadowsocks 2022 session of client.
+    client_session_id: Option<u64>,
+    last_active: Instant,
+    task: JoinHandle<()>,
+}
+
+// ClientSession is the replay state of a shadowsocks 2022 client session. It's kept
+// by session id apart from Session, so that a datagram is checked before it may
+// restart the session of its addr, and outlives Session.
+struct ClientSession {
+    window: ReplayWindow,
+    // closed is set once another session from the same addr has replaced it.
+    closed: bool,
+    last_active: Instant,
+}
+
+// UDPRelay relays encrypted datagrams between mika clients and remote addrs.
+pub struct UDPRelay {
+    idle_timeout: Duration,
+    sessions: Sessions,
+    clients: ClientSessions,
+}
+
+impl UDPRelay {
+    // UDPRelay::new creates a new UDPRelay whose sessions expire after idle_timeout.
+    pub fn new(idle_timeout: Duration) -> UDPRelay {
+        UDPRelay {
+            idle_timeout,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // serve relays datagrams received on socket.
+    pub async fn serve(
+        self,
+        socket: UdpSocket,
+        cipher: CipherKind,
+        secret_key: &[u8],
+    ) -> io::Result<()> {
+        let socket = Arc::new(socket);
+        let sweeper = tokio::spawn(sweep(
+            self.sessions.clone(),
+            self.clients.clone(),
+            self.idle_timeout,
+        ));
+
+        let mut buf = vec![0u8; MAX_UDP_PACKET_LEN];
+        let res = loop {
+            let (n, src) = match socket.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = self.relay(&socket, src, &buf[..n], cipher, secret_key) {
+                error!("udp relay from {} failed {}", src, e);
+            }
+        };
+        sweeper.abort();
+        res
+    }
+
+    // relay decrypts one client datagram and queues its payload to the session of src.
+    fn relay(
+        &self,
+        socket: &Arc<UdpSocket>,
+        src: SocketAddr,
+        packet: &[u8],
+        cipher: CipherKind,
+        secret_key: &[u8],
+    ) -> io::Result<()> {
+        let (plaintext, header) = crypto::decrypt_packet(cipher, secret_key, packet, false)?;
+        if let Some(header) = &header {
+            self.check_replay(header)?;
+        }
+        let client_session_id = header.map(|h| h.session_id);
+        let addr_len = address::raw_address_len(&plaintext)?;
+        let addr = address::parse_address_from_vec(&plaintext[..addr_len])?;
+        debug!("udp relay {} to {}", src, &addr);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        // a restarted client starts a new session from the same addr, the old one
+        // can't come back.
+        if sessions
+            .get(&src)
+            .is_some_and(|s| s.client_session_id != client_session_id)
+        {
+            if let Some(s) = sessions.remove(&src) {
+                info!("udp session {} restarts", src);
+                s.task.abort();
+                if let Some(id) = s.client_session_id {
+                    if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+                        client.closed = true;
+                    }
+                }
+            }
+        }
+        let session = sessions.entry(src).or_insert_with(|| {
+            self.new_session(socket, src, client_session_id, cipher, secret_key)
+        });
+        session.last_active = Instant::now();
+        match session
+            .queue
+            .try_send((addr, plaintext[addr_len..].to_vec()))
+        {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("udp session {} is busy, drop datagram", src),
+            Err(TrySendError::Closed(_)) => {
+                sessions.remove(&src);
+                return Err(io::Error::other("udp session closed"));
+            }
+        }
+        Ok(())
+    }
+
+    // check_replay records the packet id of header in the window of its session. It
+    // fails if the packet was seen or the session was closed.
+    fn check_replay(&self, header: &PacketHeader) -> io::Result<()> {
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients
+            .entry(header.session_id)
+            .or_insert_with(|| ClientSession {
+                window: ReplayWindow::new(),
+                closed: false,
+                last_active: Instant::now(),
+            });
+        if client.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("udp session {:x} is closed", header.session_id),
+            ));
+        }
+        if !client.window.check(header.packet_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("replayed packet {}", header.packet_id),
+            ));
+        }
+        client.last_active = Instant::now();
+        Ok(())
+    }
+
+    // new_session starts the task relaying the datagrams of client.
+    fn new_session(
+        &self,
+        socket: &Arc<UdpSocket>,
+        client: SocketAddr,
+        client_session_id: Option<u64>,
+        cipher: CipherKind,
+        secret_key: &[u8],
+    ) -> Session {
+        info!("udp session {} starts", client);
+        let udp_session = match client_session_id {
+            Some(id) => UdpSession::new_server(id),
+            None => UdpSession::new(),
+        };
+        let (queue, datagrams) = mpsc::channel(SESSION_QUEUE_LEN);
+        let back = RelayBack {
+            socket: socket.clone(),
+            client,
+            sessions: self.sessions.clone(),
+            cipher,
+            secret_key: secret_key.to_vec(),
+            udp_session: Arc::new(udp_session),
+        };
+        Session {
+            queue,
+            client_session_id,
+            last_active: Instant::now(),
+            task: tokio::spawn(run_session(datagrams, back)),
+        }
+    }
+}
+
+// RelayBack is what the outbound sockets of a session need to answer its client.
+#[derive(Clone)]
+struct RelayBack {
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    sessions: Sessions,
+    cipher: CipherKind,
+    secret_key: Vec<u8>,
+    udp_session: Arc<UdpSession>,
+}
+
+// Tasks are aborted when dropped, along with the session task owning them.
+struct Tasks(Vec<JoinHandle<()>>);
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for task in self.0.iter() {
+            task.abort();
+        }
+    }
+}
+
+// run_session resolves the targets of datagrams and sends them from the outbound
+// socket of their family, bound on first use.
+async fn run_session(mut datagrams: mpsc::Receiver<Datagram>, back: RelayBack) {
+    let mut outbound_v4: Option<Arc<UdpSocket>> = None;
+    let mut outbound_v6: Option<Arc<UdpSocket>> = None;
+    let mut resolved: HashMap<(String, u16), SocketAddr> = HashMap::new();
+    let mut tasks = Tasks(Vec::new());
+
+    while let Some((addr, data)) = datagrams.recv().await {
+        let target = match addr {
+            address::Address::SocketAddr(target) => target,
+            address::Address::DomainAddr(host, port) => match resolved.get(&(host.clone(), port)) {
+                Some(target) => *target,
+                None => {
+                    let target = match address::Address::DomainAddr(host.clone(), port)
+                        .resolve()
+                        .await
+                    {
+                        Ok(target) => target,
+                        Err(e) => {
+                            error!("udp resolve {} for {} failed {}", host, back.client, e);
+                            continue;
+                        }
+                    };
+                    if resolved.len() >= MAX_RESOLVED {
+                        resolved.clear();
+                    }
+                    resolved.insert((host, port), target);
+                    target
+                }
+            },
+        };
+
+        let (outbound, bind_addr) = if target.is_ipv6() {
+            (&mut outbound_v6, "[::]:0")
+        } else {
+            (&mut outbound_v4, "0.0.0.0:0")
+        };
+        let socket = match outbound {
+            Some(socket) => socket.clone(),
+            None => {
+                let socket = match UdpSocket::bind(bind_addr).await {
+                    Ok(socket) => Arc::new(socket),
+                    Err(e) => {
+                        error!("udp bind for {} failed {}", back.client, e);
+                        continue;
+                    }
+                };
+                tasks
+                    .0
+                    .push(tokio::spawn(relay_back(socket.clone(), back.clone())));
+                *outbound = Some(socket.clone());
+                socket
+            }
+        };
+        if let Err(e) = socket.send_to(&data, target).await {
+            error!("udp send to {} for {} failed {}", target, back.client, e);
+        }
+    }
+}
+
+// relay_back encrypts datagrams from remote addrs and sends them back to the client.
+async fn relay_back(outbound: Arc<UdpSocket>, back: RelayBack) {
+    let mut buf = vec![0u8; MAX_UDP_PACKET_LEN];
+    loop {
+        let (n, mut src) = match outbound.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("udp recv for {} failed {}", back.client, e);
+                return;
+            }
+        };
+        if let Some(s) = back.sessions.lock().unwrap().get_mut(&back.client) {
+            s.last_active = Instant::now();
+        }
+        if let IpAddr::V6(ip) = src.ip() {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                src.set_ip(IpAddr::V4(ip));
+            }
+        }
+
+        let mut plaintext = address::socket_addr_to_vec(&src);
+        plaintext.extend_from_slice(&buf[..n]);
+        let packet = match crypto::encrypt_packet(
+            back.cipher,
+            &back.secret_key,
+            &back.udp_session,
+            &plaintext,
+        ) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("udp encrypt for {} failed {}", back.client, e);
+                continue;
+            }
+        };
+        if let Err(e) = back.socket.send_to(&packet, back.client).await {
+            error!("udp send to {} failed {}", back.client, e);
+        }
+    }
+}
+
+// sweep periodically removes sessions idle for longer than idle_timeout, and replay
+// windows once their datagrams can't be replayed anymore.
+async fn sweep(sessions: Sessions, clients: ClientSessions, idle_timeout: Duration) {
+    let retention = Duration::max(idle_timeout, REPLAY_RETENTION);
+    let mut interval = tokio::time::interval(idle_timeout / 2);
+    loop {
+        interval.tick().await;
+        sessions.lock().unwrap().retain(|client, s| {
+            if s.last_active.elapsed() < idle_timeout {
+                return true;
+            }
+            info!("udp session {} expired", client);
+            s.task.abort();
+            false
+        });
+        clients
+            .lock()
+            .unwrap()
+            .retain(|_, c| c.last_active.elapsed() < retention);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // wait_until polls cond until it holds, failing after a few seconds.
+    async fn wait_until(cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            assert!(Instant::now() < deadline, "condition not met in time");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    // echo_target starts a target echoing datagrams back.
+    async fn echo_target() -> SocketAddr {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (n, src) = target.recv_from(&mut buf).await.unwrap();
+                let mut reply = b"echo:".to_vec();
+                reply.extend_from_slice(&buf[..n]);
+                target.send_to(&reply, src).await.unwrap();
+            }
+        });
+        target_addr
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay() {
+        let cipher = CipherKind::Aes128Gcm;
+        let key = cipher.derive_key("foobar").unwrap();
+        let target_addr = echo_target().await;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = socket.local_addr().unwrap();
+        let relay = UDPRelay::new(Duration::from_millis(200));
+        let sessions = relay.sessions.clone();
+        let relay_key = key.clone();
+        tokio::spawn(async move { relay.serve(socket, cipher, &relay_key).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let session = UdpSession::new();
+        let send = |target: &address::Address, data: &[u8]| {
+            let mut plaintext = address::address_to_vec(target);
+            plaintext.extend_from_slice(data);
+            crypto::encrypt_packet(cipher, &key, &session, &plaintext).unwrap()
+        };
+        let recv = || async {
+            let mut buf = [0u8; 1024];
+            let (n, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            crypto::decrypt_packet(cipher, &key, &buf[..n], true)
+                .unwrap()
+                .0
+        };
+
+        let mut expected = address::socket_addr_to_vec(&target_addr);
+        expected.extend_from_slice(b"echo:hi");
+        let by_ip = address::Address::SocketAddr(target_addr);
+        client
+            .send_to(&send(&by_ip, b"hi"), relay_addr)
+            .await
+            .unwrap();
+        assert_eq!(recv().await, expected);
+        assert_eq!(sessions.lock().unwrap().len(), 1);
+
+        // domains are resolved by the session, which keeps its outbound socket.
+        let by_domain = address::Address::DomainAddr("127.0.0.1".to_string(), target_addr.port());
+        client
+            .send_to(&send(&by_domain, b"hi"), relay_addr)
+            .await
+            .unwrap();
+        assert_eq!(recv().await, expected);
+        assert_eq!(sessions.lock().unwrap().len(), 1);
+
+        // an idle session expires and a new one starts on the next datagram.
+        wait_until(|| sessions.lock().unwrap().is_empty()).await;
+        client
+            .send_to(&send(&by_ip, b"hi"), relay_addr)
+            .await
+            .unwrap();
+        assert_eq!(recv().await, expected);
+        assert_eq!(sessions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_session_restart() {
+        let cipher: CipherKind = "2022-blake3-aes-128-gcm".parse().unwrap();
+        let key = cipher.derive_key("AAECAwQFBgcICQoLDA0ODw==").unwrap();
+        let target = address::Address::SocketAddr(echo_target().await);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = socket.local_addr().unwrap();
+        let relay = UDPRelay::new(Duration::from_secs(60));
+        let sessions = relay.sessions.clone();
+        let relay_key = key.clone();
+        tokio::spawn(async move { relay.serve(socket, cipher, &relay_key).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let seal = |session: &UdpSession, data: &[u8]| {
+            let mut plaintext = address::address_to_vec(&target);
+            plaintext.extend_from_slice(data);
+            crypto::encrypt_packet(cipher, &key, session, &plaintext).unwrap()
+        };
+        // roundtrip sends packet and returns the payload of the next echo.
+        let roundtrip = |packet: Vec<u8>| {
+            let client = &client;
+            let key = &key;
+            async move {
+                client.send_to(&packet, relay_addr).await.unwrap();
+                let mut buf = [0u8; 1024];
+                let (n, _) =
+                    tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                let (plaintext, header) =
+                    crypto::decrypt_packet(cipher, key, &buf[..n], true).unwrap();
+                let addr_len = address::raw_address_len(&plaintext).unwrap();
+                (plaintext[addr_len..].to_vec(), header.unwrap())
+            }
+        };
+        let session_of = || {
+            sessions
+                .lock()
+                .unwrap()
+                .get(&client_addr)
+                .unwrap()
+                .client_session_id
+        };
+
+        let old = UdpSession::new();
+        let captured = seal(&old, b"old");
+        let (echo, header) = roundtrip(captured.clone()).await;
+        assert_eq!(echo, b"echo:old");
+        assert_eq!(header.client_session_id, Some(old.id()));
+
+        // the client restarts with a new session from the same addr.
+        let new = UdpSession::new();
+        let replayed = seal(&new, b"new");
+        assert_eq!(roundtrip(replayed.clone()).await.0, b"echo:new");
+        assert_eq!(session_of(), Some(new.id()));
+
+        // neither a replayed datagram nor a fresh one of the old session restarts it,
+        // and neither is relayed: the next echo answers the last datagram.
+        client.send_to(&captured, relay_addr).await.unwrap();
+        client
+            .send_to(&seal(&old, b"old again"), relay_addr)
+            .await
+            .unwrap();
+        client.send_to(&replayed, relay_addr).await.unwrap();
+        let (echo, header) = roundtrip(seal(&new, b"still new")).await;
+        assert_eq!(echo, b"echo:still new");
+        assert_eq!(header.client_session_id, Some(new.id()));
+        assert_eq!(session_of(), Some(new.id()));
+        assert_eq!(sessions.lock().unwrap().len(), 1);
+    }
+}
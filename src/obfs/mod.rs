@@ -2,13 +2,21 @@ use std::io::{Error, ErrorKind};
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
+use std::time::SystemTime;
 
 use base64;
 use bstr::ByteSlice;
+use bytes::{Buf, BytesMut};
 use futures::ready;
 use rand::RngCore;
 use tokio::io;
-use tokio::io::ReadBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio_util::io::poll_read_buf;
+
+use crate::config::ObfsMode;
+use crate::transport::websocket::{accept_key, header};
+
+mod tls;
 
 struct Obfs {
     init: bool,
@@ -46,8 +54,7 @@ impl Obfs {
         return str.to_string();
     }
 
-    #[allow(dead_code)]
-    fn obfs_http_response(&mut self) -> String {
+    fn obfs_http_response(&mut self, key: &str) -> String {
         if self.init {
             return "".to_string();
         }
@@ -55,35 +62,212 @@ impl Obfs {
 
         let mut str = format!("HTTP/1.1 101 Switching Protocols\r\n");
         str += &format!("Server: nginx/1.{}.{}\r\n", 1, 2);
-        str += &format!("Date: {}\r\n", "2020-01-12");
+        str += &format!("Date: {}\r\n", httpdate::fmt_http_date(SystemTime::now()));
         str += &format!("Upgrade: websocket\r\n");
         str += &format!("Connection: Upgrade\r\n");
-        str += &format!("Sec-WebSocket-Accept: {}\r\n", "abc");
+        str += &format!("Sec-WebSocket-Accept: {}\r\n", accept_key(key));
         str += &format!("\r\n").to_string();
         return str.to_string();
     }
 }
 
+// accept reads the obfs handshake of a client to host, replies to it and returns the
+// reader of the stream following the handshake.
+pub async fn accept<R, W>(
+    mut reader: R,
+    writer: &mut W,
+    host: &str,
+    mode: ObfsMode,
+) -> io::Result<ObfsReader<R>>
+where
+    R: io::AsyncRead + std::marker::Unpin,
+    W: io::AsyncWrite + std::marker::Unpin,
+{
+    let mut buf = BytesMut::with_capacity(MAX_HEAD_LEN);
+    let remaining = match mode {
+        ObfsMode::Http => {
+            accept_http(&mut reader, writer, host, &mut buf).await?;
+            0
+        }
+        ObfsMode::Tls => accept_tls(&mut reader, writer, host, &mut buf).await?,
+    };
+    Ok(ObfsReader {
+        reader,
+        mode,
+        buf,
+        inited: true,
+        remaining,
+    })
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// accept_http reads the obfs HTTP request of a client, checks its Host against host
+// and replies with the 101 response. The data following the request is left in buf.
+async fn accept_http<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    host: &str,
+    buf: &mut BytesMut,
+) -> io::Result<()>
+where
+    R: io::AsyncRead + std::marker::Unpin,
+    W: io::AsyncWrite + std::marker::Unpin,
+{
+    let end = loop {
+        if let Some(idx) = buf.find("\r\n\r\n") {
+            break idx + 4;
+        }
+        if buf.len() >= MAX_HEAD_LEN {
+            return Err(invalid("obfs request too long"));
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+    };
+
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("invalid obfs request"))?;
+    if !head.starts_with("GET ") {
+        return Err(invalid("invalid obfs request"));
+    }
+    match header(head, "Host") {
+        Some(h) if h.eq_ignore_ascii_case(host) => {}
+        _ => return Err(invalid("obfs host mismatches")),
+    }
+    if !header(head, "Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+        return Err(invalid("obfs request isn't an upgrade"));
+    }
+    let key = header(head, "Sec-WebSocket-Key").ok_or_else(|| invalid("missing websocket key"))?;
+
+    let response = Obfs::new(host.to_string()).obfs_http_response(key);
+    writer.write_all(response.as_bytes()).await?;
+    buf.advance(end);
+    Ok(())
+}
+
+// accept_tls reads the client hello of a client, checks its server name against host
+// and replies with the server hello. The first payload carried in the session ticket
+// is left in buf, followed by the records read after the hello, and its length is
+// returned.
+async fn accept_tls<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    host: &str,
+    buf: &mut BytesMut,
+) -> io::Result<usize>
+where
+    R: io::AsyncRead + std::marker::Unpin,
+    W: io::AsyncWrite + std::marker::Unpin,
+{
+    let end = loop {
+        match tls::record_len(buf) {
+            Some(len) if buf.len() >= len => break len,
+            Some(len) => buf.reserve(len - buf.len()),
+            None => {}
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+    };
+
+    let hello = tls::parse_client_hello(&buf[..end])?;
+    match hello.server_name {
+        Some(name) if name.eq_ignore_ascii_case(host.as_bytes()) => {}
+        _ => return Err(invalid("obfs host mismatches")),
+    }
+    let mut response = Vec::with_capacity(256);
+    tls::server_hello(hello.session_id, &mut response);
+    writer.write_all(&response).await?;
+
+    // the ticket replaces the hello, so that it's read as application data.
+    let ticket = hello.ticket.to_vec();
+    buf.advance(end - ticket.len());
+    buf[..ticket.len()].copy_from_slice(&ticket);
+    Ok(ticket.len())
+}
+
 pub struct ObfsWriter<T>
 where
     T: io::AsyncWrite + std::marker::Unpin,
 {
     writer: T,
+    mode: ObfsMode,
     inited: bool,
+    // finished is set once a tls client has sent its finished message.
+    finished: bool,
     obfs: Obfs,
+    // buf holds framed bytes not yet written, pos is the length written.
+    buf: Vec<u8>,
+    pos: usize,
 }
 
 impl<T> ObfsWriter<T>
 where
     T: io::AsyncWrite + std::marker::Unpin,
 {
-    pub fn new(writer: T, obfs_url: String) -> ObfsWriter<T> {
+    // ObfsWriter::new creates the writer of a client sending its handshake to obfs_url first.
+    pub fn new(writer: T, obfs_url: String, mode: ObfsMode) -> ObfsWriter<T> {
         ObfsWriter {
             writer,
+            mode,
             inited: false,
+            finished: false,
             obfs: Obfs::new(obfs_url),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // ObfsWriter::new_response creates the writer of a server, whose handshake is sent by accept.
+    pub fn new_response(writer: T, mode: ObfsMode) -> ObfsWriter<T> {
+        ObfsWriter {
+            inited: true,
+            finished: true,
+            ..ObfsWriter::new(writer, String::new(), mode)
         }
     }
+
+    // frame appends the framed leading part of data to buf and returns its length.
+    fn frame(&mut self, data: &[u8]) -> usize {
+        match self.mode {
+            ObfsMode::Http => {
+                let request = self.obfs.obfs_http_request(data.len());
+                self.buf.extend_from_slice(request.as_bytes());
+                self.buf.extend_from_slice(data);
+                data.len()
+            }
+            ObfsMode::Tls if !self.inited => {
+                let n = usize::min(data.len(), tls::max_ticket_len(&self.obfs.host));
+                tls::client_hello(&self.obfs.host, &data[..n], &mut self.buf);
+                n
+            }
+            ObfsMode::Tls => {
+                if !self.finished {
+                    tls::finished(&mut self.buf);
+                    self.finished = true;
+                }
+                let n = usize::min(data.len(), tls::MAX_RECORD_LEN);
+                tls::application_data(&data[..n], &mut self.buf);
+                n
+            }
+        }
+    }
+
+    // poll_write_buf writes all framed bytes.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.buf.len() {
+            let n = ready!(Pin::new(&mut self.writer).poll_write(cx, &self.buf[self.pos..]))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.pos += n;
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Ok(()).into()
+    }
 }
 
 impl<T> io::AsyncWrite for ObfsWriter<T>
@@ -96,23 +280,29 @@ where
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
         let this = Pin::into_inner(self);
+        ready!(this.poll_write_buf(cx))?;
 
-        if !this.inited {
-            ready!(Pin::new(&mut this.writer)
-                .poll_write(cx, this.obfs.obfs_http_request(buf.len()).as_bytes()))?;
-            this.inited = true;
+        // http requests are followed by the stream as it is.
+        if this.inited && this.mode == ObfsMode::Http {
+            return Pin::new(&mut this.writer).poll_write(cx, buf);
         }
 
-        Pin::new(&mut this.writer).poll_write(cx, buf)
+        let n = this.frame(buf);
+        this.inited = true;
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Err(e).into();
+        }
+        Ok(n).into()
     }
 
-    #[inline]
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        Pin::new(&mut Pin::into_inner(self).writer).poll_flush(cx)
+        let this = Pin::into_inner(self);
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
     }
 
-    #[inline]
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
         Pin::new(&mut Pin::into_inner(self).writer).poll_shutdown(cx)
     }
 }
@@ -122,44 +312,102 @@ where
     T: io::AsyncRead + std::marker::Unpin,
 {
     reader: T,
-    buf: [u8; MAX_BUF_LEN],
-    size: usize,
-    pos: usize,
+    mode: ObfsMode,
+    // buf holds the bytes read ahead, which are the stream following the http head,
+    // or tls records.
+    buf: BytesMut,
     inited: bool,
+    // remaining is the payload left of current tls application data record.
+    remaining: usize,
 }
 
-const MAX_BUF_LEN: usize = 256;
+// The head of obfs requests and responses is small, a longer one isn't from a peer.
+const MAX_HEAD_LEN: usize = 4096;
 
 impl<T> ObfsReader<T>
 where
     T: io::AsyncRead + std::marker::Unpin,
 {
-    pub fn new(reader: T) -> ObfsReader<T> {
+    // ObfsReader::new creates the reader of a client, skipping the handshake of server.
+    pub fn new(reader: T, mode: ObfsMode) -> ObfsReader<T> {
         ObfsReader {
             reader,
-            buf: [0; MAX_BUF_LEN],
-            size: 0,
-            pos: 0,
+            mode,
+            buf: BytesMut::new(),
             inited: false,
+            remaining: 0,
+        }
+    }
+
+    // poll_read_more reads more bytes into buf, it fails if the stream ends.
+    fn poll_read_more(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.buf
+            .reserve(tls::RECORD_HEADER_LEN + tls::MAX_RECORD_LEN);
+        let n = ready!(poll_read_buf(Pin::new(&mut self.reader), cx, &mut self.buf))?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into()).into();
         }
+        Ok(()).into()
     }
 
     fn poll_read_http_response(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         loop {
-            let last = self.size;
-            let mut read_buf = ReadBuf::new(&mut self.buf[self.size..]);
-            ready!(Pin::new(&mut self.reader).poll_read(cx, &mut read_buf)?);
-            self.size += read_buf.filled().len();
-            if self.size == last {
-                return Err(ErrorKind::UnexpectedEof.into()).into();
+            if let Some(idx) = self.buf.find("\r\n\r\n") {
+                self.buf.advance(idx + 4);
+                return Ok(()).into();
             }
-            let idx = self.buf[..self.size].find("\r\n\r\n");
-            if idx.is_some() {
-                self.pos = idx.unwrap() + 4;
-                break;
+            if self.buf.len() >= MAX_HEAD_LEN {
+                return Err(invalid("obfs response too long")).into();
             }
+            ready!(self.poll_read_more(cx))?;
+        }
+    }
+
+    // poll_read_tls reads the payload of application data records, skipping the
+    // handshake records.
+    fn poll_read_tls(
+        &mut self,
+        cx: &mut Context<'_>,
+        rbuf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.remaining > 0 && !self.buf.is_empty() {
+                let len = usize::min(self.remaining, self.buf.len());
+                let len = usize::min(len, rbuf.remaining());
+                rbuf.put_slice(&self.buf[..len]);
+                self.buf.advance(len);
+                self.remaining -= len;
+                return Ok(()).into();
+            }
+
+            if self.remaining == 0 {
+                if let Some(len) = tls::record_len(&self.buf) {
+                    match self.buf[0] {
+                        tls::APPLICATION_DATA => {
+                            self.buf.advance(tls::RECORD_HEADER_LEN);
+                            self.remaining = len - tls::RECORD_HEADER_LEN;
+                            continue;
+                        }
+                        tls::HANDSHAKE | tls::CHANGE_CIPHER_SPEC if self.buf.len() >= len => {
+                            self.buf.advance(len);
+                            continue;
+                        }
+                        tls::HANDSHAKE | tls::CHANGE_CIPHER_SPEC => {}
+                        _ => return Err(invalid("unexpected tls record")).into(),
+                    }
+                } else if self.buf.is_empty() {
+                    // the stream may end between records.
+                    self.buf
+                        .reserve(tls::RECORD_HEADER_LEN + tls::MAX_RECORD_LEN);
+                    ready!(poll_read_buf(Pin::new(&mut self.reader), cx, &mut self.buf))?;
+                    if self.buf.is_empty() {
+                        return Ok(()).into();
+                    }
+                    continue;
+                }
+            }
+            ready!(self.poll_read_more(cx))?;
         }
-        Ok(()).into()
     }
 }
 
@@ -174,18 +422,76 @@ where
     ) -> Poll<io::Result<()>> {
         let this = Pin::into_inner(self);
 
+        if this.mode == ObfsMode::Tls {
+            return this.poll_read_tls(cx, buf);
+        }
+
         if !this.inited {
             ready!(this.poll_read_http_response(cx))?;
             this.inited = true;
         }
 
-        if this.pos < this.size {
-            let len = usize::min(buf.remaining(), this.buf[this.pos..this.size].len());
-            buf.put_slice(&(this.buf[this.pos..this.pos + len]));
-            this.pos += len;
+        if !this.buf.is_empty() {
+            let len = usize::min(buf.remaining(), this.buf.len());
+            buf.put_slice(&this.buf[..len]);
+            this.buf.advance(len);
             return Ok(()).into();
         }
 
         Pin::new(&mut this.reader).poll_read(cx, buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_accept() {
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        for mode in [ObfsMode::Http, ObfsMode::Tls] {
+            let (client, server) = io::duplex(1 << 16);
+            let (client_reader, client_writer) = io::split(client);
+            let (server_reader, mut server_writer) = io::split(server);
+
+            let mut writer = ObfsWriter::new(client_writer, "www.bing.com".to_string(), mode);
+            let expected = data.clone();
+            tokio::spawn(async move {
+                writer.write_all(b"hello").await.unwrap();
+                writer.write_all(&expected).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+            let mut reader = accept(server_reader, &mut server_writer, "www.bing.com", mode)
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(&buf[..5], b"hello");
+            assert_eq!(&buf[5..], &data[..]);
+
+            let mut writer = ObfsWriter::new_response(server_writer, mode);
+            writer.write_all(b"world").await.unwrap();
+            writer.shutdown().await.unwrap();
+            let mut reader = ObfsReader::new(client_reader, mode);
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"world");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_host_mismatch() {
+        for mode in [ObfsMode::Http, ObfsMode::Tls] {
+            let (client, server) = io::duplex(4096);
+            let (_, client_writer) = io::split(client);
+            let (server_reader, mut server_writer) = io::split(server);
+            let mut writer = ObfsWriter::new(client_writer, "www.baidu.com".to_string(), mode);
+            writer.write_all(b"hello").await.unwrap();
+            let err = accept(server_reader, &mut server_writer, "www.bing.com", mode)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+// Package tls frames a stream as a TLS 1.2 session the way simple-obfs does: the client
+// hello carries the first payload in its session ticket, and everything after the
+// handshake is carried in application data records.
+use std::io::{Error, ErrorKind, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::BufMut;
+use rand::RngCore;
+
+pub(super) const CHANGE_CIPHER_SPEC: u8 = 0x14;
+pub(super) const HANDSHAKE: u8 = 0x16;
+pub(super) const APPLICATION_DATA: u8 = 0x17;
+
+pub(super) const RECORD_HEADER_LEN: usize = 5;
+// A record carries 2^14 bytes at most.
+pub(super) const MAX_RECORD_LEN: usize = 1 << 14;
+
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_HELLO: u8 = 0x02;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SESSION_TICKET: u16 = 0x0023;
+
+const CIPHER_SUITES: [u8; 56] = [
+    0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f,
+    0x00, 0x9e, 0xc0, 0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a,
+    0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09, 0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d, 0x00, 0x9c, 0x00, 0x3d,
+    0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x00, 0xff,
+];
+
+// ec_point_formats, supported_groups, signature_algorithms, encrypt_then_mac and
+// extended_master_secret extensions following the server name.
+const CLIENT_EXTENSIONS: [u8; 66] = [
+    0x00, 0x0b, 0x00, 0x04, 0x03, 0x01, 0x00, 0x02, 0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d,
+    0x00, 0x17, 0x00, 0x19, 0x00, 0x18, 0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02,
+    0x06, 0x03, 0x05, 0x01, 0x05, 0x02, 0x05, 0x03, 0x04, 0x01, 0x04, 0x02, 0x04, 0x03, 0x03, 0x01,
+    0x03, 0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02, 0x03, 0x00, 0x16, 0x00, 0x00, 0x00, 0x17,
+    0x00, 0x00,
+];
+
+// renegotiation_info, extended_master_secret and ec_point_formats extensions.
+const SERVER_EXTENSIONS: [u8; 15] = [
+    0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+];
+
+// The client hello takes this many bytes besides the server name and session ticket.
+const CLIENT_HELLO_LEN: usize = RECORD_HEADER_LEN
+    + 4
+    + 2
+    + 32
+    + 1
+    + 32
+    + 2
+    + CIPHER_SUITES.len()
+    + 2
+    + 2
+    + 4
+    + 9
+    + CLIENT_EXTENSIONS.len();
+
+// max_ticket_len returns the largest first payload fitting in the client hello record.
+pub(super) fn max_ticket_len(host: &str) -> usize {
+    (RECORD_HEADER_LEN + MAX_RECORD_LEN).saturating_sub(CLIENT_HELLO_LEN + host.len())
+}
+
+// put_random puts the random of hello, which starts with the unix time.
+fn put_random(dst: &mut Vec<u8>) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default();
+    dst.put_u32(now);
+    let mut random = [0u8; 28];
+    rand::thread_rng().fill_bytes(&mut random);
+    dst.put_slice(&random);
+}
+
+// put_u24 puts the 3 bytes length of a handshake message.
+fn put_u24(dst: &mut Vec<u8>, n: usize) {
+    dst.put_u8((n >> 16) as u8);
+    dst.put_u16(n as u16);
+}
+
+// client_hello appends the client hello to host carrying ticket to dst.
+pub(super) fn client_hello(host: &str, ticket: &[u8], dst: &mut Vec<u8>) {
+    let ext_len = 4 + ticket.len() + 9 + host.len() + CLIENT_EXTENSIONS.len();
+    let hello_len = 2 + 32 + 1 + 32 + 2 + CIPHER_SUITES.len() + 2 + 2 + ext_len;
+
+    dst.put_u8(HANDSHAKE);
+    dst.put_u16(0x0301);
+    dst.put_u16((4 + hello_len) as u16);
+    dst.put_u8(CLIENT_HELLO);
+    put_u24(dst, hello_len);
+    dst.put_u16(0x0303);
+    put_random(dst);
+    let mut session_id = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut session_id);
+    dst.put_u8(session_id.len() as u8);
+    dst.put_slice(&session_id);
+    dst.put_u16(CIPHER_SUITES.len() as u16);
+    dst.put_slice(&CIPHER_SUITES);
+    // null compression only.
+    dst.put_slice(&[0x01, 0x00]);
+
+    dst.put_u16(ext_len as u16);
+    dst.put_u16(EXT_SESSION_TICKET);
+    dst.put_u16(ticket.len() as u16);
+    dst.put_slice(ticket);
+    dst.put_u16(EXT_SERVER_NAME);
+    dst.put_u16((host.len() + 5) as u16);
+    dst.put_u16((host.len() + 3) as u16);
+    dst.put_u8(0);
+    dst.put_u16(host.len() as u16);
+    dst.put_slice(host.as_bytes());
+    dst.put_slice(&CLIENT_EXTENSIONS);
+}
+
+// server_hello appends the server hello echoing session_id, followed by the change
+// cipher spec and finished messages, to dst.
+pub(super) fn server_hello(session_id: &[u8], dst: &mut Vec<u8>) {
+    let hello_len = 2 + 32 + 1 + session_id.len() + 2 + 1 + 2 + SERVER_EXTENSIONS.len();
+
+    dst.put_u8(HANDSHAKE);
+    dst.put_u16(0x0301);
+    dst.put_u16((4 + hello_len) as u16);
+    dst.put_u8(SERVER_HELLO);
+    put_u24(dst, hello_len);
+    dst.put_u16(0x0303);
+    put_random(dst);
+    dst.put_u8(session_id.len() as u8);
+    dst.put_slice(session_id);
+    // TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256 without compression.
+    dst.put_slice(&[0xcc, 0xa8, 0x00]);
+    dst.put_u16(SERVER_EXTENSIONS.len() as u16);
+    dst.put_slice(&SERVER_EXTENSIONS);
+    finished(dst);
+}
+
+// finished appends the change cipher spec and an encrypted-looking finished message.
+pub(super) fn finished(dst: &mut Vec<u8>) {
+    dst.put_slice(&[CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01]);
+    let mut verify_data = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut verify_data);
+    dst.put_u8(HANDSHAKE);
+    dst.put_u16(0x0303);
+    dst.put_u16(verify_data.len() as u16);
+    dst.put_slice(&verify_data);
+}
+
+// application_data appends payload framed as one record to dst.
+pub(super) fn application_data(payload: &[u8], dst: &mut Vec<u8>) {
+    dst.put_u8(APPLICATION_DATA);
+    dst.put_u16(0x0303);
+    dst.put_u16(payload.len() as u16);
+    dst.put_slice(payload);
+}
+
+// record_len returns the length of the record starting buf, or None until its header is read.
+pub(super) fn record_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    Some(RECORD_HEADER_LEN + u16::from_be_bytes([buf[3], buf[4]]) as usize)
+}
+
+// ClientHello is the part of a client hello read by server.
+pub(super) struct ClientHello<'a> {
+    pub session_id: &'a [u8],
+    pub server_name: Option<&'a [u8]>,
+    pub ticket: &'a [u8],
+}
+
+fn invalid() -> Error {
+    Error::new(ErrorKind::InvalidData, "invalid tls client hello")
+}
+
+// take splits n bytes off the front of buf.
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if buf.len() < n {
+        return Err(invalid());
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(head)
+}
+
+fn take_u8(buf: &mut &[u8]) -> Result<usize> {
+    Ok(take(buf, 1)?[0] as usize)
+}
+
+fn take_u16(buf: &mut &[u8]) -> Result<usize> {
+    let b = take(buf, 2)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]) as usize)
+}
+
+// parse_client_hello parses the record of a client hello.
+pub(super) fn parse_client_hello(record: &[u8]) -> Result<ClientHello<'_>> {
+    let mut buf = record;
+    let header = take(&mut buf, RECORD_HEADER_LEN)?;
+    if header[0] != HANDSHAKE {
+        return Err(invalid());
+    }
+    let handshake = take(&mut buf, 4)?;
+    if handshake[0] != CLIENT_HELLO {
+        return Err(invalid());
+    }
+    take(&mut buf, 2 + 32)?;
+    let session_id_len = take_u8(&mut buf)?;
+    let session_id = take(&mut buf, session_id_len)?;
+    let suites_len = take_u16(&mut buf)?;
+    take(&mut buf, suites_len)?;
+    let compression_len = take_u8(&mut buf)?;
+    take(&mut buf, compression_len)?;
+
+    let ext_len = take_u16(&mut buf)?;
+    let mut exts = take(&mut buf, ext_len)?;
+    let mut hello = ClientHello {
+        session_id,
+        server_name: None,
+        ticket: &[],
+    };
+    while !exts.is_empty() {
+        let ext_type = take_u16(&mut exts)? as u16;
+        let len = take_u16(&mut exts)?;
+        let mut data = take(&mut exts, len)?;
+        match ext_type {
+            EXT_SESSION_TICKET => hello.ticket = data,
+            EXT_SERVER_NAME => {
+                take(&mut data, 2 + 1)?;
+                let name_len = take_u16(&mut data)?;
+                hello.server_name = Some(take(&mut data, name_len)?);
+            }
+            _ => {}
+        }
+    }
+    Ok(hello)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_hello() {
+        let host = "www.bing.com";
+        let ticket = vec![0x5a; max_ticket_len(host)];
+        let mut record = Vec::new();
+        client_hello(host, &ticket, &mut record);
+        assert_eq!(record.len(), RECORD_HEADER_LEN + MAX_RECORD_LEN);
+        assert_eq!(record_len(&record), Some(record.len()));
+
+        let hello = parse_client_hello(&record).unwrap();
+        assert_eq!(hello.server_name, Some(host.as_bytes()));
+        assert_eq!(hello.ticket, &ticket[..]);
+        assert_eq!(hello.session_id.len(), 32);
+        assert!(parse_client_hello(&record[..record.len() - 1]).is_err());
+    }
+}
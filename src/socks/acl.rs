@@ -3,8 +3,6 @@ use crate::config::{ACLConfig, MatchMode, Policy};
 use std::str::FromStr;
 use std::sync::RwLock;
 
-use log::debug;
-
 pub struct ACLManager {
     rules: RwLock<ACLConfig>,
 }
@@ -16,19 +14,23 @@ impl ACLManager {
         }
     }
 
-    fn domain_keyword(x: &str, addr: &address::Address) -> bool {
+    fn domain_keyword(x: &str, addr: &address::Address, _user: &str) -> bool {
         addr.is_domain() && addr.domain().contains(x)
     }
 
-    fn domain_suffix(x: &str, addr: &address::Address) -> bool {
+    fn domain_suffix(x: &str, addr: &address::Address, _user: &str) -> bool {
         addr.is_domain() && addr.domain().ends_with(x)
     }
 
-    fn domain(x: &str, addr: &address::Address) -> bool {
+    fn domain(x: &str, addr: &address::Address, _user: &str) -> bool {
         addr.is_domain() && addr.domain() == x
     }
 
-    fn ip_cidr(x: &str, addr: &address::Address) -> bool {
+    fn user(x: &str, _addr: &address::Address, user: &str) -> bool {
+        !user.is_empty() && user == x
+    }
+
+    fn ip_cidr(x: &str, addr: &address::Address, _user: &str) -> bool {
         if addr.is_domain() {
             return false;
         }
@@ -36,22 +38,24 @@ impl ACLManager {
         ip_cidr.contains(&addr.ip_addr())
     }
 
-    fn get_match_fn(mode: &MatchMode) -> fn(&str, &address::Address) -> bool {
+    fn get_match_fn(mode: &MatchMode) -> fn(&str, &address::Address, &str) -> bool {
         match mode {
             MatchMode::DomainKeyword => ACLManager::domain_keyword,
             MatchMode::DomainSuffix => ACLManager::domain_suffix,
             MatchMode::Domain => ACLManager::domain,
             MatchMode::IpCidr => ACLManager::ip_cidr,
+            MatchMode::User => ACLManager::user,
         }
     }
 
-    pub fn acl(&self, _addr: &address::Address) -> Policy {
+    // acl returns the policy for addr requested by user, user is empty if not authenticated.
+    pub fn acl(&self, _addr: &address::Address, user: &str) -> Policy {
         let rules = &self.rules.read().unwrap();
 
         for rule in rules.rules.iter() {
             let match_fn = ACLManager::get_match_fn(&rule.mode);
             for pattern in rule.pattern.iter() {
-                if match_fn(pattern, _addr) {
+                if match_fn(pattern, _addr, user) {
                     return rule.policy.clone();
                 }
             }
@@ -59,3 +63,25 @@ impl ACLManager {
         rules.fnl.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyRule;
+
+    #[test]
+    fn test_acl_user() {
+        let acl = ACLManager::new(ACLConfig {
+            rules: vec![ProxyRule {
+                pattern: vec!["alice".to_string()],
+                mode: MatchMode::User,
+                policy: Policy::Direct,
+            }],
+            fnl: Policy::Proxy,
+        });
+        let addr = address::get_address_from_url("example.com".to_string(), 443).unwrap();
+        assert_eq!(acl.acl(&addr, "alice"), Policy::Direct);
+        assert_eq!(acl.acl(&addr, "bob"), Policy::Proxy);
+        assert_eq!(acl.acl(&addr, ""), Policy::Proxy);
+    }
+}
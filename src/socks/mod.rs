@@ -2,36 +2,71 @@
 use log::{debug, error, info};
 use tokio::io;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 use crate::address;
-use crate::config::Policy;
+use crate::config::{Local, Policy};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub mod acl;
 pub mod server;
+pub mod udp;
+mod v4;
 
-const SOCKS_V5: u8 = 0x05;
+pub const SOCKS_V4: u8 = 0x04;
+pub const SOCKS_V5: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCEEDED: u8 = 0x00;
+const AUTH_FAILED: u8 = 0x01;
 
 const CONNECT: u8 = 0x01;
 const BIND: u8 = 0x02;
 const UDP_ASSOCIATE: u8 = 0x03;
 
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// BIND waits this long for the inbound connection.
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
 // TCPRelay as a socks5 server and mika client.
 pub struct TCPRelay {
     acl_manager: Arc<acl::ACLManager>,
     server_manager: Arc<server::ServerManager>,
+    local: Arc<Local>,
+    // user authenticated by hand_shake, empty if no authentication is required.
+    user: String,
+    // socks version spoken by the client, replies are formed accordingly.
+    version: u8,
 }
 
 impl TCPRelay {
-    // TCPRelay::new creates a new Socks5 TCPRelay.
+    // TCPRelay::new creates a new Socks5 TCPRelay serving the local listener.
     pub fn new(
         acl_manager: Arc<acl::ACLManager>,
         server_manager: Arc<server::ServerManager>,
+        local: Arc<Local>,
     ) -> TCPRelay {
         TCPRelay {
             acl_manager,
             server_manager,
+            local,
+            user: String::new(),
+            version: SOCKS_V5,
         }
     }
 
@@ -41,14 +76,11 @@ impl TCPRelay {
 
         // get cmd and address
         let (cmd, addr) = self.parse_request(&mut conn).await?;
-        self.reply(&mut conn).await?;
 
         match cmd {
-            CONNECT => {
-                self.connect(conn, addr).await?;
-            }
-            UDP_ASSOCIATE => self.udp_associate(&mut conn).await,
-            BIND => {}
+            CONNECT => self.connect(conn, addr).await?,
+            UDP_ASSOCIATE => self.udp_associate(conn).await?,
+            BIND => self.bind(conn, addr).await?,
             _ => {}
         }
         Ok(())
@@ -74,20 +106,93 @@ impl TCPRelay {
 
         if ver != SOCKS_V5 {
             error!("Error version {}", ver);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported socks version {}", ver),
+            ));
         }
 
         // read all method identifier octets
         let nmethods: usize = conn.read_u8().await? as usize;
         debug!("Socks method {}", nmethods);
 
-        let mut raw = [0u8; 257];
-        conn.read_exact(&mut raw[2..2 + nmethods]).await?;
+        let mut methods = [0u8; 255];
+        conn.read_exact(&mut methods[..nmethods]).await?;
+
+        let method = if self.local.users.is_empty() {
+            METHOD_NO_AUTH
+        } else {
+            METHOD_USERNAME_PASSWORD
+        };
+        if !methods[..nmethods].contains(&method) {
+            conn.write_all(&[SOCKS_V5, METHOD_NO_ACCEPTABLE]).await?;
+            return Err(io::Error::other("no acceptable socks5 method"));
+        }
 
         // reply to socks5 client
-        conn.write(&[SOCKS_V5, 0x00]).await?;
+        conn.write_all(&[SOCKS_V5, method]).await?;
+        if method == METHOD_USERNAME_PASSWORD {
+            self.authenticate(conn).await?;
+        }
+        Ok(())
+    }
+
+    // username/password request (RFC 1929)
+    // +----+------+----------+------+----------+
+    // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    // +----+------+----------+------+----------+
+    // | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    // +----+------+----------+------+----------+
+    // reply:
+    // +----+--------+
+    // |VER | STATUS |
+    // +----+--------+
+    // | 1  |   1    |
+    // +----+--------+
+    // authenticate checks username and password against the users of local listener.
+    async fn authenticate(&mut self, conn: &mut TcpStream) -> io::Result<()> {
+        let ver = conn.read_u8().await?;
+        if ver != AUTH_VERSION {
+            conn.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+            return Err(io::Error::other(format!("error auth version {}", ver)));
+        }
+
+        let ulen = conn.read_u8().await? as usize;
+        let mut username = vec![0u8; ulen];
+        conn.read_exact(&mut username).await?;
+        let plen = conn.read_u8().await? as usize;
+        let mut password = vec![0u8; plen];
+        conn.read_exact(&mut password).await?;
+
+        let username = String::from_utf8_lossy(&username).to_string();
+        let authenticated = self
+            .local
+            .users
+            .iter()
+            .any(|u| u.verify(&username, &password));
+        if !authenticated {
+            conn.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("socks5 user {} authentication failed", username),
+            ));
+        }
+
+        conn.write_all(&[AUTH_VERSION, AUTH_SUCCEEDED]).await?;
+        debug!("socks5 user {} authenticated", username);
+        self.user = username;
         Ok(())
     }
 
+    // user_name returns the authenticated user for logging.
+    fn user_name(&self) -> &str {
+        if self.user.is_empty() {
+            "anonymous"
+        } else {
+            &self.user
+        }
+    }
+
     // The SOCKS request is formed as follows:
     //         +----+-----+-------+------+----------+----------+
     //         |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
@@ -113,6 +218,10 @@ impl TCPRelay {
         let ver = conn.read_u8().await?;
         if ver != SOCKS_V5 {
             error!("Error version {}", ver);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported socks version {}", ver),
+            ));
         }
         conn.read_u8().await
     }
@@ -127,9 +236,11 @@ impl TCPRelay {
             CONNECT | BIND | UDP_ASSOCIATE => {}
             _ => {
                 error!("unknown cmd type");
+                self.reply(conn, REP_COMMAND_NOT_SUPPORTED, &unspecified())
+                    .await?;
                 return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "unsupported address type",
+                    io::ErrorKind::Unsupported,
+                    "unsupported cmd type",
                 ));
             }
         }
@@ -137,7 +248,16 @@ impl TCPRelay {
         // RSV
         conn.read_u8().await?;
 
-        let addr = address::get_raw_address(conn).await?;
+        let addr = match address::get_raw_address(conn).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::Unsupported {
+                    self.reply(conn, REP_ADDRESS_NOT_SUPPORTED, &unspecified())
+                        .await?;
+                }
+                return Err(e);
+            }
+        };
         Ok((cmd, addr))
     }
 
@@ -167,62 +287,106 @@ impl TCPRelay {
     //              o  IP V6 address: X’04’
     //           o  BND.ADDR       server bound address
     //           o  BND.PORT       server bound port in network octet order
-    async fn reply(&mut self, conn: &mut TcpStream) -> io::Result<()> {
-        conn.write(&[
-            SOCKS_V5,
-            0x00,
-            0x00,
-            address::IPV4_ADDR,
-            0x00,
-            0x00,
-            0x00,
-            0x00,
-            0x10,
-            0x10,
-        ])
-        .await?;
-        Ok(())
+    async fn reply(&self, conn: &mut TcpStream, rep: u8, bnd_addr: &SocketAddr) -> io::Result<()> {
+        if self.version == SOCKS_V4 {
+            return self.reply_v4(conn, rep, bnd_addr).await;
+        }
+
+        let mut reply = vec![SOCKS_V5, rep, 0x00];
+        reply.extend(address::socket_addr_to_vec(bnd_addr));
+        conn.write_all(&reply).await
     }
 
+    // connect handles CONNECT cmd.
+    // The reply is deferred until the outbound connection has an outcome.
     async fn connect(self, mut conn: TcpStream, addr: Vec<u8>) -> io::Result<()> {
         let parsed_addr = address::parse_address_from_vec(&addr)?;
 
-        match self.acl_manager.acl(&parsed_addr) {
+        match self.acl_manager.acl(&parsed_addr, &self.user) {
             Policy::Direct => {
-                info!("directly connect to {}", &parsed_addr);
-                let server = parsed_addr.new_conn().await?;
-                let (mut cr, mut cw) = conn.into_split();
-                let (mut server_reader, mut server_writer) = server.into_split();
-
-                tokio::spawn(async move {
-                    let _ = io::copy(&mut server_reader, &mut cw).await;
-                });
-                let _ = io::copy(&mut cr, &mut server_writer).await;
+                info!(
+                    "directly connect to {} for {}",
+                    &parsed_addr,
+                    self.user_name()
+                );
+                let server = match parsed_addr.new_conn().await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        self.reply(&mut conn, reply_code(&e), &unspecified())
+                            .await?;
+                        return Err(e);
+                    }
+                };
+                self.reply(&mut conn, REP_SUCCEEDED, &server.local_addr()?)
+                    .await?;
+                relay(conn, server).await;
                 Ok(())
             }
-            Policy::Reject => conn.shutdown().await,
+            Policy::Reject => {
+                info!("reject {} for {}", &parsed_addr, self.user_name());
+                self.reply(&mut conn, REP_NOT_ALLOWED, &unspecified())
+                    .await?;
+                conn.shutdown().await
+            }
             Policy::Proxy => self.connect_by_proxy(conn, addr, None).await,
             Policy::ProxyGroup(pg) => self.connect_by_proxy(conn, addr, Some(pg)).await,
         }
     }
 
-    // connect handles CONNECT cmd
+    // connect_by_proxy handles CONNECT cmd by proxy.
     // Here is a bit magic. It acts as a mika client that redirects connection to mika server.
     async fn connect_by_proxy(
         self,
-        conn: TcpStream,
+        mut conn: TcpStream,
         addr: Vec<u8>,
         pg: Option<String>,
     ) -> io::Result<()> {
-        let (mut cr, mut cw) = conn.into_split();
-        let (mut server_writer, mut server_reader, remote_addr) =
-            self.server_manager.pick_one(pg).await?;
+        let parsed_addr = address::parse_address_from_vec(&addr)?;
+        let (mut server_writer, mut server_reader, remote_addr, bnd_addr) =
+            match self.server_manager.pick_one(pg.clone(), &parsed_addr).await {
+                Ok(server) => server,
+                Err(e) => {
+                    self.reply(&mut conn, REP_GENERAL_FAILURE, &unspecified())
+                        .await?;
+                    return Err(e);
+                }
+            };
+        self.reply(&mut conn, REP_SUCCEEDED, &bnd_addr).await?;
 
-        server_writer.write(addr.as_slice()).await?;
+        let (mut cr, mut cw) = conn.into_split();
+        server_writer.write_all(addr.as_slice()).await?;
+        server_writer.flush().await?;
 
-        let parsed_addr = address::parse_address_from_vec(&addr)?;
-        info!("connect to {} via {}", &parsed_addr, remote_addr);
+        info!(
+            "connect to {} via {} for {}",
+            &parsed_addr,
+            remote_addr,
+            self.user_name()
+        );
+        // the client has got its reply, so the stream isn't retried on another server.
+        // A server failing to read before its first byte while the client is still there
+        // cools down if it's in a fallback group. One closing cleanly may have had its
+        // target close without a response, which says nothing of the server.
+        let client_closed = Arc::new(AtomicBool::new(false));
+        let closed = client_closed.clone();
+        let server_manager = self.server_manager.clone();
         tokio::spawn(async move {
+            let mut buf = vec![0u8; 8192];
+            let n = match server_reader.read(&mut buf).await {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) => {
+                    if !closed.load(Ordering::Relaxed) {
+                        server_manager.cool_down(pg.as_deref(), &remote_addr);
+                    }
+                    error!("io remote copy failed {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = cw.write_all(&buf[..n]).await {
+                error!("io remote copy failed {}", e);
+                return;
+            }
             if let Err(e) = io::copy(&mut server_reader, &mut cw).await {
                 error!("io remote copy failed {}", e);
             }
@@ -231,24 +395,238 @@ impl TCPRelay {
         if let Err(e) = io::copy(&mut cr, &mut server_writer).await {
             error!("io client copy failed {}", e);
         }
+        client_closed.store(true, Ordering::Relaxed);
         Ok(())
     }
 
-    // udp_associate handles UDP_ASSOCIATE cmd
-    async fn udp_associate(&mut self, conn: &mut TcpStream) {
-        let _ = conn
-            .write(&[
-                SOCKS_V5,
-                0x00,
-                0x00,
-                address::IPV4_ADDR,
-                0x00,
-                0x00,
-                0x00,
-                0x00,
-                0x04,
-                0x38,
-            ])
-            .await;
+    // bind handles BIND cmd.
+    // It listens on an ephemeral port, replies with it, accepts one inbound connection,
+    // replies with the peer address and relays between the two connections.
+    // Only the Direct policy is supported, mika server has no BIND counterpart.
+    async fn bind(self, mut conn: TcpStream, addr: Vec<u8>) -> io::Result<()> {
+        let parsed_addr = address::parse_address_from_vec(&addr)?;
+        let unspecified = unspecified();
+
+        match self.acl_manager.acl(&parsed_addr, &self.user) {
+            Policy::Direct => {}
+            Policy::Reject => {
+                return self.reply(&mut conn, REP_NOT_ALLOWED, &unspecified).await;
+            }
+            Policy::Proxy | Policy::ProxyGroup(_) => {
+                debug!("bind {} by proxy is not supported", &parsed_addr);
+                return self
+                    .reply(&mut conn, REP_COMMAND_NOT_SUPPORTED, &unspecified)
+                    .await;
+            }
+        }
+
+        let local_ip = conn.local_addr()?.ip();
+        let listener = TcpListener::bind(SocketAddr::new(local_ip, 0)).await?;
+        let bnd_addr = listener.local_addr()?;
+        self.reply(&mut conn, REP_SUCCEEDED, &bnd_addr).await?;
+        info!(
+            "bind at {} to {} for {}",
+            bnd_addr,
+            &parsed_addr,
+            self.user_name()
+        );
+
+        let (inbound, peer) =
+            match tokio::time::timeout(BIND_ACCEPT_TIMEOUT, listener.accept()).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    self.reply(&mut conn, REP_GENERAL_FAILURE, &unspecified)
+                        .await?;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.reply(&mut conn, REP_GENERAL_FAILURE, &unspecified)
+                        .await?;
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            };
+        drop(listener);
+        info!("bind at {} accepted {}", bnd_addr, peer);
+
+        self.reply(&mut conn, REP_SUCCEEDED, &peer).await?;
+        relay(conn, inbound).await;
+        Ok(())
+    }
+
+    // udp_associate handles UDP_ASSOCIATE cmd.
+    // It binds a UDP socket on the interface the client connected to, replies with its address,
+    // and relays datagrams until the controlling TCP connection is closed.
+    async fn udp_associate(self, mut conn: TcpStream) -> io::Result<()> {
+        let local_ip = conn.local_addr()?.ip();
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+        let bnd_addr = socket.local_addr()?;
+        self.reply(&mut conn, REP_SUCCEEDED, &bnd_addr).await?;
+        info!("udp associate at {}", bnd_addr);
+
+        let relay = udp::UDPRelay::new(self.acl_manager, self.server_manager, self.user);
+        relay.serve(conn, socket).await
+    }
+}
+
+// relay copies data between the socks5 client and remote connection.
+async fn relay(conn: TcpStream, remote: TcpStream) {
+    let (mut cr, mut cw) = conn.into_split();
+    let (mut remote_reader, mut remote_writer) = remote.into_split();
+
+    tokio::spawn(async move {
+        let _ = io::copy(&mut remote_reader, &mut cw).await;
+    });
+    let _ = io::copy(&mut cr, &mut remote_writer).await;
+}
+
+// unspecified returns the BND.ADDR used in replies that carry no address.
+fn unspecified() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+// reply_code maps a connect error to the socks5 reply field.
+fn reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ACLConfig;
+    use crate::crypto::ReplayFilter;
+
+    // new_relay creates a relay of the local listener described by yaml.
+    pub(super) fn new_relay(local: &str) -> TCPRelay {
+        new_relay_with_policy(local, Policy::Direct)
+    }
+
+    // new_relay_with_policy creates a relay applying fnl to every target.
+    fn new_relay_with_policy(local: &str, fnl: Policy) -> TCPRelay {
+        let acl_manager = acl::ACLManager::new(ACLConfig { rules: vec![], fnl });
+        let replay_filter = Arc::new(ReplayFilter::new(1000, 1e-6).unwrap());
+        let server_manager = server::ServerManager::new(vec![], vec![], replay_filter).unwrap();
+        TCPRelay::new(
+            Arc::new(acl_manager),
+            Arc::new(server_manager),
+            Arc::new(serde_yaml::from_str(local).unwrap()),
+        )
+    }
+
+    // connected returns both ends of a loopback connection, the client's first.
+    async fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        (client, conn)
+    }
+
+    // read_reply reads a socks5 reply with an ipv4 BND.ADDR.
+    async fn read_reply(client: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], 5);
+        assert_eq!(reply[2..4], [0, 1]);
+        let ip = [reply[4], reply[5], reply[6], reply[7]];
+        (
+            reply[1],
+            SocketAddr::from((ip, u16::from_be_bytes([reply[8], reply[9]]))),
+        )
+    }
+
+    // hand_shake runs the handshake of relay against a client sending request, and
+    // returns its result, the user authenticated and what the client received.
+    async fn hand_shake(mut relay: TCPRelay, request: &[u8]) -> (io::Result<()>, String, Vec<u8>) {
+        let (mut client, mut conn) = connected().await;
+        client.write_all(request).await.unwrap();
+        let res = relay.hand_shake(&mut conn).await;
+        drop(conn);
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (res, relay.user, reply)
+    }
+
+    #[tokio::test]
+    async fn test_hand_shake() {
+        let auth = "{address: 127.0.0.1, port: 0, users: [{username: alice, password: secret}]}";
+        let no_auth = "{address: 127.0.0.1, port: 0}";
+
+        let (res, user, reply) = hand_shake(new_relay(no_auth), &[5, 1, 0]).await;
+        assert!(res.is_ok());
+        assert_eq!(user, "");
+        assert_eq!(reply, [5, 0]);
+
+        // a method required but not offered has no acceptable method.
+        let (res, _, reply) = hand_shake(new_relay(auth), &[5, 1, 0]).await;
+        assert!(res.is_err());
+        assert_eq!(reply, [5, 0xFF]);
+        let (res, _, reply) = hand_shake(new_relay(no_auth), &[5, 1, 2]).await;
+        assert!(res.is_err());
+        assert_eq!(reply, [5, 0xFF]);
+
+        let (res, user, reply) =
+            hand_shake(new_relay(auth), b"\x05\x02\x00\x02\x01\x05alice\x06secret").await;
+        assert!(res.is_ok());
+        assert_eq!(user, "alice");
+        assert_eq!(reply, [5, 2, 1, 0]);
+
+        for request in [
+            &b"\x05\x01\x02\x01\x05alice\x06secreT"[..],
+            b"\x05\x01\x02\x01\x05alice\x07secrets",
+            b"\x05\x01\x02\x01\x05alice\x00",
+            b"\x05\x01\x02\x01\x03bob\x06secret",
+        ] {
+            let (res, user, reply) = hand_shake(new_relay(auth), request).await;
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(user, "");
+            assert_eq!(reply, [5, 2, 1, 1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let local = "{address: 127.0.0.1, port: 0}";
+        let dst = address::socket_addr_to_vec(&SocketAddr::from(([127, 0, 0, 1], 80)));
+
+        let (mut client, conn) = connected().await;
+        let task = tokio::spawn(new_relay(local).bind(conn, dst.clone()));
+        // the first reply has the address listened on.
+        let (rep, bnd_addr) = read_reply(&mut client).await;
+        assert_eq!(rep, REP_SUCCEEDED);
+        assert_eq!(bnd_addr.ip().to_string(), "127.0.0.1");
+        assert_ne!(bnd_addr.port(), 0);
+
+        // the second one has the address of the peer connecting to it.
+        let mut peer = TcpStream::connect(bnd_addr).await.unwrap();
+        let (rep, peer_addr) = read_reply(&mut client).await;
+        assert_eq!(rep, REP_SUCCEEDED);
+        assert_eq!(peer_addr, peer.local_addr().unwrap());
+        assert!(TcpStream::connect(bnd_addr).await.is_err());
+
+        let mut buf = [0u8; 4];
+        client.write_all(b"ping").await.unwrap();
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        peer.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+        drop(client);
+        assert!(task.await.unwrap().is_ok());
+
+        // mika servers have no BIND, so proxy policies aren't supported.
+        for policy in [Policy::Proxy, Policy::ProxyGroup("Proxy".to_string())] {
+            let (mut client, conn) = connected().await;
+            let relay = new_relay_with_policy(local, policy);
+            assert!(relay.bind(conn, dst.clone()).await.is_ok());
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, 0x07, 0, 1, 0, 0, 0, 0, 0, 0]);
+        }
     }
 }
@@ -1,28 +1,219 @@
+use log::{debug, info, warn};
 use rand::{thread_rng, RngCore};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::sync::RwLock;
-use tokio::io::{AsyncRead, AsyncWrite, Result};
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, Result};
 use tokio::net::TcpStream;
+use tokio::time;
+use url::{Position, Url};
 
-use crate::config::{ProxyGroup, Server};
-use crate::crypto::{CryptoReader, CryptoWriter};
+use crate::address;
+use crate::config::{HealthCheckConfig, ProxyGroup, ProxyGroupKind, Server, Strategy};
+use crate::crypto::{CryptoReader, CryptoWriter, ReplayFilter};
 use crate::obfs::{ObfsReader, ObfsWriter};
+use crate::transport::plugin::{LocalAddr, Plugin};
+use crate::transport::tls::{self, TlsConnector};
+use crate::transport::{websocket, Stream};
+
+// ServerConn is the writer and reader of a stream to server, the id of server and
+// the local address of connection.
+pub type ServerConn = (
+    Box<dyn AsyncWrite + Unpin + Send>,
+    Box<dyn AsyncRead + Unpin + Send>,
+    String,
+    SocketAddr,
+);
+
+// Probe is an http request to url made through servers to check them.
+struct Probe {
+    target: Vec<u8>,
+    request: Vec<u8>,
+    timeout: Duration,
+}
+
+impl Probe {
+    fn new(url: &str, timeout: u64) -> Result<Probe> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, msg.to_string());
+        let url = Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        if url.scheme() != "http" {
+            return Err(invalid("probe url must be http"));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid("probe url has no host"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let target = address::get_address_from_url(host.to_string(), port)?;
+        let request = format!(
+            "HEAD {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            &url[Position::BeforePath..Position::AfterQuery],
+            host
+        );
+        Ok(Probe {
+            target: address::address_to_vec(&target),
+            request: request.into_bytes(),
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+}
+
+// OpenConn counts a connection to a server as open until it's dropped.
+struct OpenConn(Arc<AtomicUsize>);
+
+impl OpenConn {
+    fn new(count: Arc<AtomicUsize>) -> OpenConn {
+        count.fetch_add(1, Ordering::Relaxed);
+        OpenConn(count)
+    }
+}
+
+impl Drop for OpenConn {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Tracked is a half of a connection to a server, which stays open until both halves
+// are dropped.
+struct Tracked<T> {
+    inner: T,
+    _conn: Arc<OpenConn>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
 
 pub struct ServerManager {
     servers: Vec<Server>,
     server_map: HashMap<String, usize>,
     proxy_groups: RwLock<HashMap<String, ProxyGroupState>>,
+    // replay_filter rejects replayed response streams.
+    replay_filter: Arc<ReplayFilter>,
+    // tls_connectors are the TLS clients of servers with tls, by server id.
+    tls_connectors: HashMap<String, TlsConnector>,
+    // plugin_addrs are the local addresses of plugins, by server id.
+    plugin_addrs: HashMap<String, LocalAddr>,
+    // url_tests are the url-test groups.
+    url_tests: Vec<ProxyGroup>,
+    // round_robin is the count of picks of round-robin groups, by group id.
+    round_robin: HashMap<String, AtomicUsize>,
+    // connections is the count of open connections, by server id.
+    connections: HashMap<String, Arc<AtomicUsize>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProxyGroupState {
     id: String,
+    #[serde(default, rename = "type")]
+    kind: ProxyGroupKind,
+    #[serde(default)]
+    strategy: Strategy,
+    // cooldown is the seconds a member of a fallback group is skipped after failing.
+    #[serde(default)]
+    cooldown: u64,
     proxy_list: Vec<Proxy>,
     selected_idx: usize,
 }
 
 impl ProxyGroupState {
+    // selected returns the selected proxy if it's enabled and alive.
+    fn selected(&self) -> Option<&Proxy> {
+        self.proxy_list
+            .get(self.selected_idx)
+            .filter(|p| p.enabled && p.alive)
+    }
+
+    // select_fastest selects the proxy with the lowest latency, unless the selected one
+    // is within tolerance milliseconds of it. It returns whether the selection changed.
+    fn select_fastest(&mut self, tolerance: u64) -> bool {
+        let usable = |p: &Proxy| p.enabled && p.alive && p.latency.is_some();
+        let best = self
+            .proxy_list
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| usable(p))
+            .min_by_key(|(_, p)| p.latency);
+        let (best_idx, best_latency) = match best {
+            Some((idx, p)) => (idx, p.latency.unwrap_or_default()),
+            None => return false,
+        };
+        if let Some(selected) = self.proxy_list.get(self.selected_idx).filter(|p| usable(p)) {
+            if selected.latency.unwrap_or_default() <= best_latency + tolerance {
+                return false;
+            }
+        }
+        let changed = best_idx != self.selected_idx;
+        self.selected_idx = best_idx;
+        changed
+    }
+
+    // available_proxy_list returns the enabled proxies which are alive, or all enabled
+    // ones if none is alive.
+    fn available_proxy_list(&self) -> Vec<Proxy> {
+        let proxies = self.enabled_proxy_list();
+        let alive: Vec<Proxy> = proxies.iter().filter(|p| p.alive).cloned().collect();
+        if alive.is_empty() {
+            proxies
+        } else {
+            alive
+        }
+    }
+
+    // fallback_list returns the available proxies in order, those cooling down last.
+    fn fallback_list(&self) -> Vec<Proxy> {
+        let now = Instant::now();
+        let (ready, cooling): (Vec<Proxy>, Vec<Proxy>) = self
+            .available_proxy_list()
+            .into_iter()
+            .partition(|p| !matches!(p.cooldown_until, Some(until) if until > now));
+        ready.into_iter().chain(cooling).collect()
+    }
+
     fn enabled_proxy_list(&self) -> Vec<Proxy> {
         let mut proxies: Vec<Proxy> = Vec::new();
         for proxy in self.proxy_list.iter() {
@@ -40,14 +231,80 @@ pub struct ProxyGroupStatePatch {
     proxy: Proxy,
 }
 
+// ProxyGroupSelection selects proxy in the select group id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxyGroupSelection {
+    id: String,
+    proxy: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Proxy {
     id: String,
     enabled: bool,
+    // alive turns false once health checks fail max_fails times in a row, and true
+    // again on the first success.
+    #[serde(default = "default_alive")]
+    alive: bool,
+    #[serde(default)]
+    fail_streak: u32,
+    // latency is the milliseconds taken by the last url test, None if it failed.
+    #[serde(default)]
+    latency: Option<u64>,
+    // weight is the share of picks of weighted groups.
+    #[serde(default = "default_weight")]
+    weight: u32,
+    // cooldown_until is when a member of a fallback group which failed is tried first
+    // again.
+    #[serde(skip)]
+    cooldown_until: Option<Instant>,
+}
+
+fn default_alive() -> bool {
+    true
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Proxy {
+    fn new(id: String, weight: u32) -> Proxy {
+        Proxy {
+            id,
+            enabled: true,
+            alive: true,
+            fail_streak: 0,
+            latency: None,
+            weight,
+            cooldown_until: None,
+        }
+    }
 }
 
 impl ServerManager {
-    pub fn new(servers: Vec<Server>, _proxy_group: Vec<ProxyGroup>) -> Self {
+    pub fn new(
+        servers: Vec<Server>,
+        _proxy_group: Vec<ProxyGroup>,
+        replay_filter: Arc<ReplayFilter>,
+    ) -> Result<Self> {
+        let mut tls_connectors = HashMap::new();
+        let mut plugin_addrs = HashMap::new();
+        for server in servers.iter() {
+            if let Some(tls_cfg) = &server.tls {
+                tls_connectors.insert(server.id.clone(), tls::connector(tls_cfg)?);
+            }
+            if !server.plugin.is_empty() {
+                let plugin = Plugin::new(
+                    &server.plugin,
+                    &server.plugin_opts,
+                    &server.address,
+                    server.port as u16,
+                )?;
+                plugin_addrs.insert(server.id.clone(), plugin.start()?);
+            }
+        }
+
         let mut server_map = HashMap::new();
         let mut idx: usize = 0;
         for server in servers.iter() {
@@ -60,13 +317,13 @@ impl ServerManager {
             let grp = group.clone();
             let mut proxies: Vec<Proxy> = Vec::with_capacity(grp.proxy_list.len());
             for proxy in grp.proxy_list {
-                proxies.push(Proxy {
-                    id: proxy,
-                    enabled: true,
-                })
+                proxies.push(Proxy::new(proxy.id().to_string(), proxy.weight()))
             }
             let group_state = ProxyGroupState {
                 id: grp.id,
+                kind: grp.kind,
+                strategy: grp.strategy,
+                cooldown: grp.cooldown,
                 proxy_list: proxies,
                 selected_idx: 0,
             };
@@ -75,22 +332,42 @@ impl ServerManager {
 
         let mut group_state = ProxyGroupState {
             id: "Proxy".to_string(),
+            kind: ProxyGroupKind::Random,
+            strategy: Strategy::Random,
+            cooldown: 0,
             proxy_list: Vec::with_capacity(servers.len()),
             selected_idx: 0,
         };
         for server in servers.iter() {
-            group_state.proxy_list.push(Proxy {
-                id: server.id.clone(),
-                enabled: true,
-            });
+            group_state
+                .proxy_list
+                .push(Proxy::new(server.id.clone(), 1));
         }
         proxy_group.insert(group_state.id.clone(), group_state);
 
-        ServerManager {
+        let round_robin = proxy_group
+            .keys()
+            .map(|id| (id.clone(), AtomicUsize::new(0)))
+            .collect();
+        let connections = servers
+            .iter()
+            .map(|server| (server.id.clone(), Arc::new(AtomicUsize::new(0))))
+            .collect();
+
+        Ok(ServerManager {
             servers,
             server_map,
             proxy_groups: RwLock::new(proxy_group),
-        }
+            replay_filter,
+            tls_connectors,
+            plugin_addrs,
+            url_tests: _proxy_group
+                .into_iter()
+                .filter(|g| g.kind == ProxyGroupKind::UrlTest)
+                .collect(),
+            round_robin,
+            connections,
+        })
     }
 
     pub fn update(&self, proxy_group: &ProxyGroupStatePatch) {
@@ -111,6 +388,27 @@ impl ServerManager {
         }
     }
 
+    // select selects a proxy of a select group.
+    pub fn select(&self, selection: &ProxyGroupSelection) -> Result<()> {
+        let mut proxy_groups = self.proxy_groups.write().unwrap();
+        let group = proxy_groups
+            .get_mut(&selection.id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such proxy group"))?;
+        if group.kind != ProxyGroupKind::Select {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "proxy group isn't a select group",
+            ));
+        }
+        group.selected_idx = group
+            .proxy_list
+            .iter()
+            .position(|p| p.id == selection.proxy)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such proxy in group"))?;
+        info!("{} selects {}", selection.id, selection.proxy);
+        Ok(())
+    }
+
     pub fn get_state(&self) -> Vec<ProxyGroupState> {
         let proxy_group = self.proxy_groups.read().unwrap();
         let mut all: Vec<ProxyGroupState> = Vec::with_capacity(proxy_group.len());
@@ -121,46 +419,630 @@ impl ServerManager {
         all
     }
 
-    fn pick(&self, proxy_group_id: Option<String>) -> &Server {
+    // pick picks a server of the group to connect to target.
+    pub fn pick(&self, proxy_group_id: Option<String>, target: &address::Address) -> &Server {
         let proxy_groups = self.proxy_groups.read().unwrap();
         let group_id = proxy_group_id.unwrap_or("Proxy".to_string());
         let id = {
             let proxy_group = proxy_groups.get(&group_id).unwrap();
-            let proxy_list = proxy_group.enabled_proxy_list();
-            let idx = thread_rng().next_u32() as usize % proxy_list.len();
-            let sid = &proxy_list[idx].id;
-            self.server_map[sid]
+            let selected = match proxy_group.kind {
+                // a proxy selected by hand is used while enabled, even if it's down.
+                ProxyGroupKind::Select => proxy_group
+                    .proxy_list
+                    .get(proxy_group.selected_idx)
+                    .filter(|p| p.enabled)
+                    .cloned(),
+                ProxyGroupKind::UrlTest => proxy_group.selected().cloned(),
+                ProxyGroupKind::Fallback => proxy_group.fallback_list().into_iter().next(),
+                ProxyGroupKind::Random => None,
+            };
+            let sid = match selected {
+                Some(proxy) => proxy.id.clone(),
+                None => {
+                    let proxy_list = proxy_group.available_proxy_list();
+                    let idx = self.balance(proxy_group, &proxy_list, target);
+                    proxy_list[idx].id.clone()
+                }
+            };
+            self.server_map[&sid]
         };
 
         &self.servers[id]
     }
 
+    // balance returns the index of the proxy to pick in proxies of group according to
+    // the strategy of group.
+    fn balance(
+        &self,
+        group: &ProxyGroupState,
+        proxies: &[Proxy],
+        target: &address::Address,
+    ) -> usize {
+        let random = || thread_rng().next_u32() as usize % proxies.len();
+        match group.strategy {
+            Strategy::Random => random(),
+            Strategy::RoundRobin => match self.round_robin.get(&group.id) {
+                Some(count) => count.fetch_add(1, Ordering::Relaxed) % proxies.len(),
+                None => random(),
+            },
+            Strategy::Weighted => {
+                let total: u64 = proxies.iter().map(|p| p.weight as u64).sum();
+                if total == 0 {
+                    return random();
+                }
+                let mut n = thread_rng().next_u64() % total;
+                for (idx, proxy) in proxies.iter().enumerate() {
+                    if n < proxy.weight as u64 {
+                        return idx;
+                    }
+                    n -= proxy.weight as u64;
+                }
+                proxies.len() - 1
+            }
+            Strategy::LeastConnections => proxies
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| {
+                    self.connections
+                        .get(&p.id)
+                        .map(|c| c.load(Ordering::Relaxed))
+                        .unwrap_or_default()
+                })
+                .map(|(idx, _)| idx)
+                .unwrap_or_default(),
+            // rendezvous hashing moves only the destinations of a proxy going away.
+            Strategy::ConsistentHash => {
+                let host = target.host();
+                proxies
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, p)| {
+                        let mut hasher = DefaultHasher::new();
+                        (&host, &p.id).hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .map(|(idx, _)| idx)
+                    .unwrap_or_default()
+            }
+        }
+    }
+
+    // pick_one connects to a server of the group picked for target. Members of a
+    // fallback group are tried in order until one connects, those failing cool down.
     pub async fn pick_one(
         &self,
         proxy_group_id: Option<String>,
-    ) -> Result<(
-        Box<dyn AsyncWrite + Unpin + Send>,
-        Box<dyn AsyncRead + Unpin + Send>,
-        String,
-    )> {
-        let server_cfg = self.pick(proxy_group_id);
-
-        let server =
-            TcpStream::connect(format!("{}:{}", server_cfg.address, server_cfg.port)).await?;
-
-        let (rr, rw) = server.into_split();
-        let mut writer: Box<dyn AsyncWrite + Unpin + Send> = Box::new(rw);
-        let mut reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(rr);
+        target: &address::Address,
+    ) -> Result<ServerConn> {
+        let fallback = match proxy_group_id.as_deref() {
+            Some(group_id) => self.fallback_list(group_id),
+            None => Vec::new(),
+        };
+        if fallback.is_empty() {
+            return self.open(self.pick(proxy_group_id, target)).await;
+        }
+
+        let group_id = proxy_group_id.as_deref();
+        let mut last_err = Error::new(ErrorKind::NotFound, "no proxy in group");
+        for id in fallback {
+            let server_cfg = &self.servers[self.server_map[&id]];
+            match self.open(server_cfg).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    warn!("connect to {} failed {}, falling back", id, e);
+                    self.cool_down(group_id, &id);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    // fallback_list returns the ids of members of group_id to try in order, none if it
+    // isn't a fallback group.
+    fn fallback_list(&self, group_id: &str) -> Vec<String> {
+        let proxy_groups = self.proxy_groups.read().unwrap();
+        match proxy_groups.get(group_id) {
+            Some(group) if group.kind == ProxyGroupKind::Fallback => {
+                group.fallback_list().into_iter().map(|p| p.id).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // cool_down tries server id last in the fallback group for the cooldown of group,
+    // after it failed. It does nothing for other groups.
+    pub fn cool_down(&self, proxy_group_id: Option<&str>, id: &str) {
+        let mut proxy_groups = self.proxy_groups.write().unwrap();
+        let group = match proxy_group_id.and_then(|group_id| proxy_groups.get_mut(group_id)) {
+            Some(group) if group.kind == ProxyGroupKind::Fallback => group,
+            _ => return,
+        };
+        let until = Instant::now() + Duration::from_secs(group.cooldown);
+        for proxy in group.proxy_list.iter_mut().filter(|p| p.id == id) {
+            proxy.cooldown_until = Some(until);
+        }
+        info!("{} cools {} down for {}s", group.id, id, group.cooldown);
+    }
+
+    // open connects to server_cfg. The connection counts as open until both of its
+    // halves are dropped.
+    async fn open(&self, server_cfg: &Server) -> Result<ServerConn> {
+        let conn = match self.connections.get(&server_cfg.id) {
+            Some(count) => Arc::new(OpenConn::new(count.clone())),
+            None => return self.connect(server_cfg).await,
+        };
+        let (writer, reader, id, local_addr) = self.connect(server_cfg).await?;
+        Ok((
+            Box::new(Tracked {
+                inner: writer,
+                _conn: conn.clone(),
+            }),
+            Box::new(Tracked {
+                inner: reader,
+                _conn: conn,
+            }),
+            id,
+            local_addr,
+        ))
+    }
+
+    // connect opens the encrypted stream to server_cfg through its transports.
+    async fn connect(&self, server_cfg: &Server) -> Result<ServerConn> {
+        let server = match self.plugin_addrs.get(&server_cfg.id) {
+            Some(addr) => TcpStream::connect(addr.get()).await?,
+            None => {
+                TcpStream::connect(format!("{}:{}", server_cfg.address, server_cfg.port)).await?
+            }
+        };
+
+        let local_addr = server.local_addr()?;
+        let (mut writer, mut reader): (
+            Box<dyn AsyncWrite + Unpin + Send>,
+            Box<dyn AsyncRead + Unpin + Send>,
+        ) = match (self.tls_connectors.get(&server_cfg.id), &server_cfg.ws) {
+            (None, None) => {
+                let (rr, rw) = server.into_split();
+                (Box::new(rw), Box::new(rr))
+            }
+            (connector, ws) => {
+                let mut stream: Box<dyn Stream> = Box::new(server);
+                if let (Some(connector), Some(tls_cfg)) = (connector, &server_cfg.tls) {
+                    stream = Box::new(
+                        tls::connect(connector, stream, tls_cfg, &server_cfg.address).await?,
+                    );
+                }
+                if let Some(ws) = ws {
+                    stream = Box::new(websocket::connect(stream, ws, &server_cfg.address).await?);
+                }
+                let (rr, rw) = io::split(stream);
+                (Box::new(rw), Box::new(rr))
+            }
+        };
         if !server_cfg.obfs_url.is_empty() {
-            writer = Box::new(ObfsWriter::new(writer, server_cfg.obfs_url.clone()));
-            reader = Box::new(ObfsReader::new(reader));
+            writer = Box::new(ObfsWriter::new(
+                writer,
+                server_cfg.obfs_url.clone(),
+                server_cfg.obfs,
+            ));
+            reader = Box::new(ObfsReader::new(reader, server_cfg.obfs));
         }
 
-        let sk = server_cfg.key.clone();
+        let writer = CryptoWriter::new(writer, server_cfg.cipher, &server_cfg.key)
+            .with_coalesce(server_cfg.coalesce);
+        let reader =
+            CryptoReader::new_response(reader, server_cfg.cipher, &server_cfg.key, writer.salt())
+                .with_replay_filter(self.replay_filter.clone());
         Ok((
-            Box::new(CryptoWriter::new(writer, &server_cfg.key)),
-            Box::new(CryptoReader::new(reader, &sk)),
+            Box::new(writer),
+            Box::new(reader),
             server_cfg.id.clone(),
+            local_addr,
         ))
     }
+
+    // probe makes probe through server_cfg and returns the time taken to the response.
+    async fn probe(&self, server_cfg: &Server, probe: &Probe) -> Result<Duration> {
+        let start = Instant::now();
+        let request = async {
+            let (mut writer, mut reader, _, _) = self.connect(server_cfg).await?;
+            let mut buf = probe.target.clone();
+            buf.extend_from_slice(&probe.request);
+            writer.write_all(&buf).await?;
+            writer.flush().await?;
+
+            let mut head = [0u8; 5];
+            reader.read_exact(&mut head).await?;
+            if &head != b"HTTP/" {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "probe response isn't http",
+                ));
+            }
+            Ok(start.elapsed())
+        };
+        time::timeout(probe.timeout, request).await?
+    }
+
+    // record_health updates the health of server id in all groups with a probe result.
+    fn record_health(&self, id: &str, ok: bool, max_fails: u32) {
+        let mut proxy_groups = self.proxy_groups.write().unwrap();
+        let mut changed = None;
+        for group in proxy_groups.values_mut() {
+            for proxy in group.proxy_list.iter_mut().filter(|p| p.id == id) {
+                let alive = proxy.alive;
+                if ok {
+                    proxy.fail_streak = 0;
+                    proxy.alive = true;
+                } else {
+                    proxy.fail_streak += 1;
+                    if proxy.fail_streak >= max_fails {
+                        proxy.alive = false;
+                    }
+                }
+                if alive != proxy.alive {
+                    changed = Some(proxy.alive);
+                }
+            }
+        }
+        match changed {
+            Some(true) => info!("server {} is up", id),
+            Some(false) => warn!("server {} is down", id),
+            None => {}
+        }
+    }
+
+    // check_health probes every server each interval, servers failing max_fails probes
+    // in a row aren't picked until a probe succeeds again.
+    pub async fn check_health(self: Arc<Self>, cfg: HealthCheckConfig) -> Result<()> {
+        let probe = Probe::new(&cfg.url, cfg.timeout)?;
+        let mut ticker = time::interval(Duration::from_secs(cfg.interval));
+        loop {
+            ticker.tick().await;
+            let probes = self.servers.iter().map(|server_cfg| {
+                let result = self.probe(server_cfg, &probe);
+                async move { (&server_cfg.id, result.await) }
+            });
+            for (id, result) in futures::future::join_all(probes).await {
+                if let Err(e) = &result {
+                    debug!("health check of {} failed {}", id, e);
+                }
+                self.record_health(id, result.is_ok(), cfg.max_fails);
+            }
+        }
+    }
+
+    // start_url_tests starts measuring the latency of members of url-test groups.
+    pub fn start_url_tests(self: &Arc<Self>) -> Result<()> {
+        for group in self.url_tests.iter().filter(|g| g.interval > 0) {
+            let probe = Probe::new(&group.url, group.timeout)?;
+            tokio::spawn(self.clone().test_urls(group.clone(), probe));
+        }
+        Ok(())
+    }
+
+    // test_urls probes the members of group each interval, and selects the fastest one.
+    async fn test_urls(self: Arc<Self>, group: ProxyGroup, probe: Probe) {
+        let mut ticker = time::interval(Duration::from_secs(group.interval));
+        loop {
+            ticker.tick().await;
+            let probes = group
+                .proxy_list
+                .iter()
+                .filter_map(|proxy| self.server_map.get(proxy.id()))
+                .map(|&idx| {
+                    let server_cfg = &self.servers[idx];
+                    let result = self.probe(server_cfg, &probe);
+                    async move { (&server_cfg.id, result.await) }
+                });
+            let results = futures::future::join_all(probes).await;
+
+            let mut proxy_groups = self.proxy_groups.write().unwrap();
+            let state = match proxy_groups.get_mut(&group.id) {
+                Some(state) => state,
+                None => return,
+            };
+            for (id, result) in results {
+                if let Err(e) = &result {
+                    debug!("url test of {} in {} failed {}", id, group.id, e);
+                }
+                let latency = result.ok().map(|d| d.as_millis() as u64);
+                for proxy in state.proxy_list.iter_mut().filter(|p| &p.id == id) {
+                    proxy.latency = latency;
+                }
+            }
+            if state.select_fastest(group.tolerance) {
+                let proxy = &state.proxy_list[state.selected_idx];
+                info!(
+                    "{} selects {} at {}ms",
+                    group.id,
+                    proxy.id,
+                    proxy.latency.unwrap_or_default()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CipherKind;
+    use crate::mika::TCPRelay;
+    use tokio::net::TcpListener;
+
+    fn replay_filter() -> Arc<ReplayFilter> {
+        Arc::new(ReplayFilter::new(1000, 1e-6).unwrap())
+    }
+
+    // new_manager creates a manager of servers with ids listening at ports.
+    fn new_manager(servers: &[(&str, u16)], groups: &str) -> ServerManager {
+        let servers = servers
+            .iter()
+            .map(|(id, port)| {
+                let yaml = format!(
+                    "{{id: {}, address: 127.0.0.1, port: {}, password: foobar, method: aes-128-gcm}}",
+                    id, port
+                );
+                let mut server: Server = serde_yaml::from_str(&yaml).unwrap();
+                server.cipher = server.method.parse().unwrap();
+                server.key = server.cipher.derive_key(&server.password).unwrap();
+                server
+            })
+            .collect();
+        let groups = serde_yaml::from_str(groups).unwrap();
+        ServerManager::new(servers, groups, replay_filter()).unwrap()
+    }
+
+    fn target(host: &str) -> address::Address {
+        address::get_address_from_url(host.to_string(), 443).unwrap()
+    }
+
+    #[test]
+    fn test_health() {
+        let sm = new_manager(&[("s1", 1), ("s2", 2)], "[]");
+        sm.record_health("s1", false, 2);
+        assert!(sm.get_state()[0].proxy_list[0].alive);
+        sm.record_health("s1", false, 2);
+        assert!(!sm.get_state()[0].proxy_list[0].alive);
+        for _ in 0..20 {
+            assert_eq!(sm.pick(None, &target("a.com")).id, "s2");
+        }
+
+        // with all servers down, all are picked.
+        sm.record_health("s2", false, 1);
+        let picked: Vec<String> = (0..100)
+            .map(|_| sm.pick(None, &target("a.com")).id.clone())
+            .collect();
+        assert!(picked.iter().any(|id| id == "s1"));
+
+        sm.record_health("s1", true, 2);
+        let proxy = &sm.get_state()[0].proxy_list[0];
+        assert!(proxy.alive);
+        assert_eq!(proxy.fail_streak, 0);
+    }
+
+    #[test]
+    fn test_url_test() {
+        let sm = new_manager(
+            &[("s1", 1), ("s2", 2), ("s3", 3)],
+            "[{id: auto, type: url-test, proxy_list: [s1, s2, s3], tolerance: 10}]",
+        );
+        let record = |latencies: [Option<u64>; 3]| {
+            let mut proxy_groups = sm.proxy_groups.write().unwrap();
+            let state = proxy_groups.get_mut("auto").unwrap();
+            for (proxy, latency) in state.proxy_list.iter_mut().zip(latencies) {
+                proxy.latency = latency;
+            }
+            state.select_fastest(10)
+        };
+        let picked = || {
+            sm.pick(Some("auto".to_string()), &target("a.com"))
+                .id
+                .clone()
+        };
+
+        assert!(record([Some(100), Some(50), None]));
+        assert_eq!(picked(), "s2");
+        // s1 isn't faster by more than tolerance.
+        assert!(!record([Some(45), Some(50), None]));
+        assert_eq!(picked(), "s2");
+        assert!(record([Some(30), Some(50), Some(60)]));
+        assert_eq!(picked(), "s1");
+        // the selected proxy failing its test is replaced.
+        assert!(record([None, Some(50), Some(45)]));
+        assert_eq!(picked(), "s3");
+
+        // a selected proxy down isn't picked.
+        sm.record_health("s3", false, 1);
+        for _ in 0..20 {
+            assert_ne!(picked(), "s3");
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let sm = new_manager(
+            &[("s1", 1), ("s2", 2)],
+            "[{id: manual, type: select, proxy_list: [s1, s2]}]",
+        );
+        let picked = || {
+            sm.pick(Some("manual".to_string()), &target("a.com"))
+                .id
+                .clone()
+        };
+        let select = |id: &str, proxy: &str| {
+            sm.select(&ProxyGroupSelection {
+                id: id.to_string(),
+                proxy: proxy.to_string(),
+            })
+        };
+        for _ in 0..20 {
+            assert_eq!(picked(), "s1");
+        }
+        select("manual", "s2").unwrap();
+        // the selection holds even while the proxy is down.
+        sm.record_health("s2", false, 1);
+        for _ in 0..20 {
+            assert_eq!(picked(), "s2");
+        }
+
+        assert_eq!(
+            select("manual", "s3").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            select("other", "s1").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            select("Proxy", "s1").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(picked(), "s2");
+    }
+
+    #[test]
+    fn test_strategy() {
+        let sm = new_manager(
+            &[("s1", 1), ("s2", 2), ("s3", 3)],
+            r#"[
+                {id: rr, strategy: round-robin, proxy_list: [s1, s2, s3]},
+                {id: w, strategy: weighted, proxy_list: [{id: s1, weight: 0}, s2, {id: s3, weight: 3}]},
+                {id: lc, strategy: least-connections, proxy_list: [s1, s2, s3]},
+                {id: ch, strategy: consistent-hash, proxy_list: [s1, s2, s3]}
+            ]"#,
+        );
+        let picked =
+            |group: &str, host: &str| sm.pick(Some(group.to_string()), &target(host)).id.clone();
+
+        let rr: Vec<String> = (0..6).map(|_| picked("rr", "a.com")).collect();
+        assert_eq!(rr, ["s1", "s2", "s3", "s1", "s2", "s3"]);
+
+        let weighted: Vec<String> = (0..400).map(|_| picked("w", "a.com")).collect();
+        let count = |id: &str| weighted.iter().filter(|p| *p == id).count();
+        assert_eq!(count("s1"), 0);
+        assert!(count("s3") > count("s2"));
+
+        // open connections are counted until both halves drop.
+        let open = |id: &str| sm.connections[id].load(Ordering::Relaxed);
+        assert_eq!(picked("lc", "a.com"), "s1");
+        let conn = Arc::new(OpenConn::new(sm.connections["s1"].clone()));
+        let half = conn.clone();
+        assert_eq!(picked("lc", "a.com"), "s2");
+        drop(conn);
+        assert_eq!(open("s1"), 1);
+        drop(half);
+        assert_eq!(open("s1"), 0);
+        assert_eq!(picked("lc", "a.com"), "s1");
+
+        // a destination keeps its proxy, and only moves if that proxy goes away.
+        let hosts: Vec<String> = (0..50).map(|i| format!("{}.com", i)).collect();
+        let before: Vec<String> = hosts.iter().map(|h| picked("ch", h)).collect();
+        let after: Vec<String> = hosts.iter().map(|h| picked("ch", h)).collect();
+        assert_eq!(before, after);
+        assert!(before.iter().any(|p| p != &before[0]));
+        sm.record_health("s2", false, 1);
+        for (host, proxy) in hosts.iter().zip(before) {
+            let got = picked("ch", host);
+            if proxy == "s2" {
+                assert_ne!(got, "s2");
+            } else {
+                assert_eq!(got, proxy);
+            }
+        }
+        assert_eq!(picked("ch", "1.2.3.4"), picked("ch", "1.2.3.4"));
+    }
+
+    // relay starts a mika server of the test servers and returns its port.
+    async fn relay() -> u16 {
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let cipher = CipherKind::Aes128Gcm;
+            let key = cipher.derive_key("foobar").unwrap();
+            loop {
+                let (conn, _) = relay.accept().await.unwrap();
+                let mika = TCPRelay::new(replay_filter(), false);
+                let key = key.clone();
+                tokio::spawn(async move { mika.serve(conn, cipher, &key).await });
+            }
+        });
+        relay_port
+    }
+
+    // closed_port returns a port nothing listens on.
+    async fn closed_port() -> u16 {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        l.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let sm = new_manager(
+            &[
+                ("down", closed_port().await),
+                ("up", relay().await),
+                ("other", relay().await),
+            ],
+            "[{id: fb, type: fallback, proxy_list: [down, up, other], cooldown: 60}]",
+        );
+        let group = || Some("fb".to_string());
+        let order = || sm.fallback_list("fb");
+        assert_eq!(sm.pick(group(), &target("a.com")).id, "down");
+
+        // down fails and cools down, up is used in its place.
+        let (_, _, id, _) = sm.pick_one(group(), &target("a.com")).await.unwrap();
+        assert_eq!(id, "up");
+        assert_eq!(order(), ["up", "other", "down"]);
+        assert_eq!(sm.pick(group(), &target("a.com")).id, "up");
+
+        sm.cool_down(group().as_deref(), "up");
+        assert_eq!(order(), ["other", "down", "up"]);
+        let (_, _, id, _) = sm.pick_one(group(), &target("a.com")).await.unwrap();
+        assert_eq!(id, "other");
+
+        // members are tried again once their cooldown is over.
+        sm.proxy_groups
+            .write()
+            .unwrap()
+            .get_mut("fb")
+            .unwrap()
+            .proxy_list
+            .iter_mut()
+            .for_each(|p| p.cooldown_until = None);
+        assert_eq!(order(), ["down", "up", "other"]);
+
+        // other groups don't cool down.
+        sm.cool_down(Some("Proxy"), "up");
+        sm.cool_down(None, "up");
+        assert!(sm.fallback_list("Proxy").is_empty());
+        assert!(sm.get_state()[0]
+            .proxy_list
+            .iter()
+            .all(|p| p.cooldown_until.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        // target answers any request.
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = target.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = conn.read(&mut buf).await;
+                let _ = conn.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+            }
+        });
+
+        let sm = new_manager(
+            &[("up", relay().await), ("down", closed_port().await)],
+            "[]",
+        );
+        let probe = Probe::new(&format!("http://{}/", target_addr), 5).unwrap();
+        assert!(sm.probe(&sm.servers[0], &probe).await.is_ok());
+        assert!(sm.probe(&sm.servers[1], &probe).await.is_err());
+    }
 }
@@ -0,0 +1,611 @@
+// Package udp implements the relay behind socks5 UDP ASSOCIATE.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+
+use log::{debug, error, info};
+use tokio::io;
+use tokio::io::AsyncReadExt;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+
+use crate::address;
+use crate::config::Policy;
+use crate::crypto;
+use crate::crypto::{CipherKind, ReplayWindow, UdpSession};
+
+use super::acl;
+use super::server;
+
+const MAX_UDP_PACKET_LEN: usize = 65536;
+
+// A UDP request header is formed as follows:
+//      +----+------+------+----------+----------+----------+
+//      |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+//      +----+------+------+----------+----------+----------+
+//      | 2  |  1   |  1   | Variable |    2     | Variable |
+//      +----+------+------+----------+----------+----------+
+const UDP_HEADER_LEN: usize = 3;
+
+// The resolved domains of an association are forgotten once there are that many.
+const MAX_RESOLVED: usize = 1024;
+
+// The replay windows of server sessions are forgotten once there are that many.
+const MAX_SERVER_SESSIONS: usize = 64;
+
+type ClientAddr = Arc<Mutex<Option<SocketAddr>>>;
+type ServerKeys = Arc<RwLock<HashMap<SocketAddr, (CipherKind, Vec<u8>)>>>;
+type Resolved = Arc<Mutex<HashMap<(String, u16), SocketAddr>>>;
+type Servers = Arc<Mutex<HashMap<String, SocketAddr>>>;
+
+// Outbound holds the sockets an association sends datagrams from, ipv6 ones are
+// missing if ipv6 is unavailable.
+#[derive(Clone)]
+struct Outbound {
+    direct_v4: Arc<UdpSocket>,
+    direct_v6: Option<Arc<UdpSocket>>,
+    proxy_v4: Arc<UdpSocket>,
+    proxy_v6: Option<Arc<UdpSocket>>,
+}
+
+// socket_of returns the socket of the family of target.
+fn socket_of<'a>(
+    v4: &'a Arc<UdpSocket>,
+    v6: &'a Option<Arc<UdpSocket>>,
+    target: SocketAddr,
+) -> io::Result<&'a Arc<UdpSocket>> {
+    match (target.is_ipv4(), v6) {
+        (true, _) => Ok(v4),
+        (false, Some(socket)) => Ok(socket),
+        (false, None) => Err(io::Error::other("ipv6 is unavailable")),
+    }
+}
+
+impl Outbound {
+    // send_direct sends data to target from the direct socket of its family.
+    async fn send_direct(&self, data: &[u8], target: SocketAddr) -> io::Result<()> {
+        socket_of(&self.direct_v4, &self.direct_v6, target)?
+            .send_to(data, target)
+            .await?;
+        Ok(())
+    }
+
+    // send_proxy sends packet to a mika server from the proxy socket of its family.
+    async fn send_proxy(&self, packet: &[u8], server: SocketAddr) -> io::Result<()> {
+        socket_of(&self.proxy_v4, &self.proxy_v6, server)?
+            .send_to(packet, server)
+            .await?;
+        Ok(())
+    }
+
+    // can_proxy reports whether there's a proxy socket of the family of server.
+    fn can_proxy(&self, server: &SocketAddr) -> bool {
+        server.is_ipv4() || self.proxy_v6.is_some()
+    }
+}
+
+// parse_header returns the destination of a client datagram and the length of its
+// ATYP | DST.ADDR | DST.PORT, or None if the datagram is a fragment.
+fn parse_header(packet: &[u8]) -> io::Result<Option<(address::Address, usize)>> {
+    if packet.len() <= UDP_HEADER_LEN {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if packet[2] != 0x00 {
+        return Ok(None);
+    }
+    let raw = &packet[UDP_HEADER_LEN..];
+    let addr_len = address::raw_address_len(raw)?;
+    let addr = address::parse_address_from_vec(&raw[..addr_len])?;
+    Ok(Some((addr, addr_len)))
+}
+
+// build_packet wraps data from raw_addr with a UDP request header.
+fn build_packet(raw_addr: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(UDP_HEADER_LEN + raw_addr.len() + data.len());
+    packet.extend_from_slice(&[0x00, 0x00, 0x00]);
+    packet.extend_from_slice(raw_addr);
+    packet.extend_from_slice(data);
+    packet
+}
+
+// UDPRelay relays datagrams of one UDP association.
+pub struct UDPRelay {
+    acl_manager: Arc<acl::ACLManager>,
+    server_manager: Arc<server::ServerManager>,
+    // user authenticated on the controlling TCP connection.
+    user: String,
+    // resolved mika server addresses by server id.
+    servers: Servers,
+    // ciphers and secret keys by resolved mika server address.
+    keys: ServerKeys,
+    // session of shadowsocks 2022 methods shared by all servers.
+    session: UdpSession,
+    // resolved addresses of domains sent to directly.
+    resolved: Resolved,
+}
+
+impl UDPRelay {
+    // UDPRelay::new creates a new UDPRelay.
+    pub fn new(
+        acl_manager: Arc<acl::ACLManager>,
+        server_manager: Arc<server::ServerManager>,
+        user: String,
+    ) -> UDPRelay {
+        UDPRelay {
+            acl_manager,
+            server_manager,
+            user,
+            servers: Arc::new(Mutex::new(HashMap::new())),
+            keys: Arc::new(RwLock::new(HashMap::new())),
+            session: UdpSession::new(),
+            resolved: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // serve relays datagrams received on socket until conn is closed.
+    pub async fn serve(self, mut conn: TcpStream, socket: UdpSocket) -> io::Result<()> {
+        let client_ip = conn.peer_addr()?.ip();
+        let socket = Arc::new(socket);
+        let client_addr: ClientAddr = Arc::new(Mutex::new(None));
+
+        let outbound = Outbound {
+            direct_v4: Arc::new(UdpSocket::bind("0.0.0.0:0").await?),
+            direct_v6: UdpSocket::bind("[::]:0").await.ok().map(Arc::new),
+            proxy_v4: Arc::new(UdpSocket::bind("0.0.0.0:0").await?),
+            proxy_v6: UdpSocket::bind("[::]:0").await.ok().map(Arc::new),
+        };
+        // both proxy sockets answer the same session.
+        let responses = Arc::new(Mutex::new(Responses::new(self.session.id())));
+
+        let mut tasks = vec![
+            tokio::spawn(relay_direct_back(
+                outbound.direct_v4.clone(),
+                socket.clone(),
+                client_addr.clone(),
+            )),
+            tokio::spawn(relay_proxy_back(
+                outbound.proxy_v4.clone(),
+                socket.clone(),
+                client_addr.clone(),
+                self.keys.clone(),
+                responses.clone(),
+            )),
+        ];
+        if let Some(direct_v6) = &outbound.direct_v6 {
+            tasks.push(tokio::spawn(relay_direct_back(
+                direct_v6.clone(),
+                socket.clone(),
+                client_addr.clone(),
+            )));
+        }
+        if let Some(proxy_v6) = &outbound.proxy_v6 {
+            tasks.push(tokio::spawn(relay_proxy_back(
+                proxy_v6.clone(),
+                socket.clone(),
+                client_addr.clone(),
+                self.keys.clone(),
+                responses,
+            )));
+        }
+
+        let mut buf = vec![0u8; MAX_UDP_PACKET_LEN];
+        let res = tokio::select! {
+            res = wait_close(&mut conn) => res,
+            res = async {
+                loop {
+                    let (n, src) = socket.recv_from(&mut buf).await?;
+                    if src.ip() != client_ip {
+                        debug!("drop datagram from unknown client {}", src);
+                        continue;
+                    }
+                    *client_addr.lock().unwrap() = Some(src);
+                    if let Err(e) = self.relay(&buf[..n], &outbound).await {
+                        error!("udp relay failed {}", e);
+                    }
+                }
+            } => res,
+        };
+
+        for task in tasks {
+            task.abort();
+        }
+        info!("udp associate from {} closed", client_ip);
+        res
+    }
+
+    // relay forwards one client datagram according to the ACL of its destination.
+    async fn relay(&self, packet: &[u8], outbound: &Outbound) -> io::Result<()> {
+        let (addr, addr_len) = match parse_header(packet)? {
+            Some(header) => header,
+            None => {
+                debug!("drop fragmented datagram");
+                return Ok(());
+            }
+        };
+        let raw = &packet[UDP_HEADER_LEN..];
+
+        match self.acl_manager.acl(&addr, &self.user) {
+            Policy::Direct => {
+                debug!("directly send datagram to {}", &addr);
+                self.relay_direct(outbound, &raw[addr_len..], addr).await?;
+            }
+            Policy::Reject => debug!("reject datagram to {}", &addr),
+            Policy::Proxy => self.relay_by_proxy(outbound, raw, &addr, None).await?,
+            Policy::ProxyGroup(pg) => self.relay_by_proxy(outbound, raw, &addr, Some(pg)).await?,
+        }
+        Ok(())
+    }
+
+    // relay_direct sends data to addr. Domains are resolved once per association, in
+    // their own task so that a slow lookup doesn't hold up other datagrams.
+    async fn relay_direct(
+        &self,
+        outbound: &Outbound,
+        data: &[u8],
+        addr: address::Address,
+    ) -> io::Result<()> {
+        let (host, port) = match addr {
+            address::Address::SocketAddr(target) => {
+                return outbound.send_direct(data, target).await;
+            }
+            address::Address::DomainAddr(host, port) => (host, port),
+        };
+        let key = (host, port);
+        let cached = self.resolved.lock().unwrap().get(&key).copied();
+        if let Some(target) = cached {
+            return outbound.send_direct(data, target).await;
+        }
+
+        let outbound = outbound.clone();
+        let data = data.to_vec();
+        let resolved = self.resolved.clone();
+        tokio::spawn(async move {
+            let target = match address::Address::DomainAddr(key.0.clone(), key.1)
+                .resolve()
+                .await
+            {
+                Ok(target) => target,
+                Err(e) => {
+                    error!("udp resolve {} failed {}", key.0, e);
+                    return;
+                }
+            };
+            {
+                let mut resolved = resolved.lock().unwrap();
+                if resolved.len() >= MAX_RESOLVED {
+                    resolved.clear();
+                }
+                resolved.insert(key, target);
+            }
+            if let Err(e) = outbound.send_direct(&data, target).await {
+                error!("udp relay failed {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    // relay_by_proxy sends ATYP | DST.ADDR | DST.PORT | DATA to a mika server. Servers
+    // are resolved once per association, in their own task like domains sent to directly,
+    // to the first address there's a proxy socket for.
+    async fn relay_by_proxy(
+        &self,
+        outbound: &Outbound,
+        raw: &[u8],
+        addr: &address::Address,
+        pg: Option<String>,
+    ) -> io::Result<()> {
+        let (id, remote, cipher, key) = {
+            let server_cfg = self.server_manager.pick(pg, addr);
+            (
+                server_cfg.id.clone(),
+                format!("{}:{}", server_cfg.address, server_cfg.port),
+                server_cfg.cipher,
+                server_cfg.key.clone(),
+            )
+        };
+        let packet = crypto::encrypt_packet(cipher, &key, &self.session, raw)?;
+
+        let cached = self.servers.lock().unwrap().get(&id).copied();
+        if let Some(server) = cached {
+            debug!("send datagram via {}", id);
+            return outbound.send_proxy(&packet, server).await;
+        }
+
+        let outbound = outbound.clone();
+        let servers = self.servers.clone();
+        let keys = self.keys.clone();
+        tokio::spawn(async move {
+            let server = match lookup_host(&remote).await {
+                Ok(mut addrs) => match addrs.find(|a| outbound.can_proxy(a)) {
+                    Some(server) => server,
+                    None => {
+                        error!("udp resolve {} found no address to send from", remote);
+                        return;
+                    }
+                },
+                Err(e) => {
+                    error!("udp resolve {} failed {}", remote, e);
+                    return;
+                }
+            };
+            keys.write().unwrap().insert(server, (cipher, key));
+            servers.lock().unwrap().insert(id.clone(), server);
+            debug!("send datagram via {}", id);
+            if let Err(e) = outbound.send_proxy(&packet, server).await {
+                error!("udp relay failed {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+// wait_close waits until the controlling TCP connection is closed.
+async fn wait_close(conn: &mut TcpStream) -> io::Result<()> {
+    let mut buf = [0u8; 64];
+    while conn.read(&mut buf).await? > 0 {}
+    Ok(())
+}
+
+// send_to_client wraps data with a UDP request header and sends it to the client.
+async fn send_to_client(
+    socket: &UdpSocket,
+    client_addr: &ClientAddr,
+    raw_addr: &[u8],
+    data: &[u8],
+) -> io::Result<()> {
+    let client = match *client_addr.lock().unwrap() {
+        Some(addr) => addr,
+        None => return Ok(()),
+    };
+
+    socket
+        .send_to(&build_packet(raw_addr, data), client)
+        .await?;
+    Ok(())
+}
+
+// relay_direct_back relays datagrams from remote addrs back to the client.
+async fn relay_direct_back(direct: Arc<UdpSocket>, socket: Arc<UdpSocket>, client: ClientAddr) {
+    let mut buf = vec![0u8; MAX_UDP_PACKET_LEN];
+    loop {
+        let (n, mut src) = match direct.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("udp direct recv failed {}", e);
+                return;
+            }
+        };
+        if let IpAddr::V6(ip) = src.ip() {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                src.set_ip(IpAddr::V4(ip));
+            }
+        }
+        let raw_addr = address::socket_addr_to_vec(&src);
+        if let Err(e) = send_to_client(&socket, &client, &raw_addr, &buf[..n]).await {
+            error!("udp send to client failed {}", e);
+        }
+    }
+}
+
+// Responses opens the datagrams of mika servers to one client session.
+struct Responses {
+    // session_id is the shadowsocks 2022 session responses must answer.
+    session_id: u64,
+    // windows have the packet ids seen by server session id.
+    windows: HashMap<u64, ReplayWindow>,
+}
+
+impl Responses {
+    fn new(session_id: u64) -> Responses {
+        Responses {
+            session_id,
+            windows: HashMap::new(),
+        }
+    }
+
+    // open decrypts packet, dropping shadowsocks 2022 packets answering other sessions
+    // or replayed.
+    fn open(&mut self, cipher: CipherKind, key: &[u8], packet: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let (plaintext, header) = crypto::decrypt_packet(cipher, key, packet, true)?;
+        let header = match header {
+            Some(header) => header,
+            None => return Ok(plaintext),
+        };
+        if header.client_session_id != Some(self.session_id) {
+            return Err(invalid(format!(
+                "packet answers session {:?}",
+                header.client_session_id
+            )));
+        }
+        if !self.windows.contains_key(&header.session_id)
+            && self.windows.len() >= MAX_SERVER_SESSIONS
+        {
+            self.windows.clear();
+        }
+        let window = self.windows.entry(header.session_id).or_default();
+        if !window.check(header.packet_id) {
+            return Err(invalid(format!("replayed packet {}", header.packet_id)));
+        }
+        Ok(plaintext)
+    }
+}
+
+// relay_proxy_back decrypts datagrams from mika servers and relays them back to the client.
+async fn relay_proxy_back(
+    proxy: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    client: ClientAddr,
+    keys: ServerKeys,
+    responses: Arc<Mutex<Responses>>,
+) {
+    let mut buf = vec![0u8; MAX_UDP_PACKET_LEN];
+    loop {
+        let (n, src) = match proxy.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("udp proxy recv failed {}", e);
+                return;
+            }
+        };
+        let (cipher, key) = match keys.read().unwrap().get(&src) {
+            Some(key) => key.clone(),
+            None => {
+                debug!("drop datagram from unknown server {}", src);
+                continue;
+            }
+        };
+        let opened = responses.lock().unwrap().open(cipher, &key, &buf[..n]);
+        let plaintext = match opened {
+            Ok(p) => p,
+            Err(e) => {
+                error!("udp decrypt from {} failed {}", src, e);
+                continue;
+            }
+        };
+        if let Err(e) = send_to_client(&socket, &client, &[], &plaintext).await {
+            error!("udp send to client failed {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ACLConfig, Server};
+    use crate::crypto::ReplayFilter;
+    use crate::mika;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_header() {
+        let ipv4 = [0, 0, 0, 0x01, 1, 2, 3, 4, 0x01, 0xbb, b'h', b'i'];
+        let (addr, addr_len) = parse_header(&ipv4).unwrap().unwrap();
+        assert_eq!(addr.to_string(), "1.2.3.4:443");
+        assert_eq!(&ipv4[UDP_HEADER_LEN + addr_len..], b"hi");
+
+        let mut ipv6 = vec![0, 0, 0, 0x04];
+        ipv6.extend_from_slice(&[0u8; 15]);
+        ipv6.extend_from_slice(&[1, 0, 53, b'x']);
+        let (addr, addr_len) = parse_header(&ipv6).unwrap().unwrap();
+        assert_eq!(addr.to_string(), "[::1]:53");
+        assert_eq!(addr_len, 19);
+
+        let mut domain = vec![0, 0, 0, 0x03, 5];
+        domain.extend_from_slice(b"a.com");
+        domain.extend_from_slice(&[0, 80, b'x']);
+        let (addr, addr_len) = parse_header(&domain).unwrap().unwrap();
+        assert!(addr.is_domain());
+        assert_eq!(addr.domain(), "a.com");
+        assert_eq!(addr_len, 9);
+
+        // fragments are dropped.
+        let mut fragment = ipv4;
+        fragment[2] = 0x01;
+        assert!(parse_header(&fragment).unwrap().is_none());
+
+        assert!(parse_header(&[0, 0, 0]).is_err());
+        assert!(parse_header(&[0, 0, 0, 0x01, 1, 2]).is_err());
+        assert!(parse_header(&[0, 0, 0, 0x03, 9, b'a']).is_err());
+        assert!(parse_header(&[0, 0, 0, 0x05, 1, 2, 3, 4, 0, 80]).is_err());
+    }
+
+    #[test]
+    fn test_build_packet() {
+        let src: SocketAddr = "1.2.3.4:443".parse().unwrap();
+        let packet = build_packet(&address::socket_addr_to_vec(&src), b"hi");
+        assert_eq!(packet, [0, 0, 0, 0x01, 1, 2, 3, 4, 0x01, 0xbb, b'h', b'i']);
+        let (addr, _) = parse_header(&packet).unwrap().unwrap();
+        assert_eq!(addr.to_string(), "1.2.3.4:443");
+
+        assert_eq!(build_packet(&[], b"hi"), [0, 0, 0, b'h', b'i']);
+    }
+
+    #[test]
+    fn test_responses() {
+        let kind: CipherKind = "2022-blake3-aes-128-gcm".parse().unwrap();
+        let key = kind.derive_key("AAECAwQFBgcICQoLDA0ODw==").unwrap();
+        let client = UdpSession::new();
+        let server = UdpSession::new_server(client.id());
+        let mut responses = Responses::new(client.id());
+
+        let packet = crypto::encrypt_packet(kind, &key, &server, b"hello").unwrap();
+        assert_eq!(responses.open(kind, &key, &packet).unwrap(), b"hello");
+        // a replayed response is dropped.
+        assert!(responses.open(kind, &key, &packet).is_err());
+        let packet = crypto::encrypt_packet(kind, &key, &server, b"world").unwrap();
+        assert_eq!(responses.open(kind, &key, &packet).unwrap(), b"world");
+
+        // so are responses to another session and reflected requests.
+        let other = UdpSession::new_server(UdpSession::new().id());
+        let packet = crypto::encrypt_packet(kind, &key, &other, b"hello").unwrap();
+        assert!(responses.open(kind, &key, &packet).is_err());
+        let packet = crypto::encrypt_packet(kind, &key, &client, b"hello").unwrap();
+        assert!(responses.open(kind, &key, &packet).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_by_proxy() {
+        // target echoes datagrams back.
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (n, src) = target.recv_from(&mut buf).await.unwrap();
+                let mut reply = b"echo:".to_vec();
+                reply.extend_from_slice(&buf[..n]);
+                target.send_to(&reply, src).await.unwrap();
+            }
+        });
+
+        // the mika server listens on ipv6 only.
+        let mika_socket = UdpSocket::bind("[::1]:0").await.unwrap();
+        let yaml = format!(
+            "{{id: s1, address: '::1', port: {}, password: foobar, method: aes-128-gcm}}",
+            mika_socket.local_addr().unwrap().port()
+        );
+        let mut server: Server = serde_yaml::from_str(&yaml).unwrap();
+        server.cipher = server.method.parse().unwrap();
+        server.key = server.cipher.derive_key(&server.password).unwrap();
+        let (cipher, key) = (server.cipher, server.key.clone());
+        tokio::spawn(async move {
+            mika::udp::UDPRelay::new(Duration::from_secs(60))
+                .serve(mika_socket, cipher, &key)
+                .await
+        });
+
+        let replay_filter = Arc::new(ReplayFilter::new(1000, 1e-6).unwrap());
+        let server_manager =
+            Arc::new(server::ServerManager::new(vec![server], vec![], replay_filter).unwrap());
+        let acl_manager = Arc::new(acl::ACLManager::new(ACLConfig {
+            rules: vec![],
+            fnl: Policy::Proxy,
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = socket.local_addr().unwrap();
+        let relay = UDPRelay::new(acl_manager, server_manager, String::new());
+        tokio::spawn(relay.serve(conn, socket));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let raw_addr = address::socket_addr_to_vec(&target_addr);
+        // the server is resolved for the first datagram and cached for the next.
+        for _ in 0..2 {
+            client
+                .send_to(&build_packet(&raw_addr, b"hi"), relay_addr)
+                .await
+                .unwrap();
+            let mut buf = [0u8; 1024];
+            let (n, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], build_packet(&raw_addr, b"echo:hi"));
+        }
+    }
+}
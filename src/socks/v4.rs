@@ -0,0 +1,246 @@
+// Package v4 implements socks4 and socks4a on top of the socks5 TCPRelay.
+use std::net::SocketAddr;
+
+use log::debug;
+use tokio::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::address;
+
+use super::{
+    unspecified, TCPRelay, BIND, CONNECT, REP_COMMAND_NOT_SUPPORTED, REP_NOT_ALLOWED,
+    REP_SUCCEEDED, SOCKS_V4,
+};
+
+const REP_V4_GRANTED: u8 = 0x5A;
+const REP_V4_REJECTED: u8 = 0x5B;
+
+const MAX_CSTRING_LEN: usize = 255;
+
+impl TCPRelay {
+    // The SOCKS4 request is formed as follows:
+    //         +----+----+----+----+----+----+----+----+----+----+....+----+
+    //         | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    //         +----+----+----+----+----+----+----+----+----+----+....+----+
+    //            1    1      2              4           variable       1
+    // SOCKS4a sets DSTIP to 0.0.0.x (x != 0) and appends the null terminated domain name.
+
+    // serve_v4 handles connection between socks4 client and remote addr.
+    pub async fn serve_v4(mut self, mut conn: TcpStream) -> io::Result<()> {
+        self.version = SOCKS_V4;
+
+        let ver = conn.read_u8().await?;
+        if ver != SOCKS_V4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported socks version {}", ver),
+            ));
+        }
+        let cmd = conn.read_u8().await?;
+        let port = conn.read_u16().await?;
+        let mut ip = [0u8; 4];
+        conn.read_exact(&mut ip).await?;
+        let userid = read_cstring(&mut conn).await?;
+        debug!("Socks4 cmd {} userid {:?}", cmd, userid);
+
+        let mut addr = Vec::with_capacity(2 + MAX_CSTRING_LEN + 2);
+        if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+            let host = read_cstring(&mut conn).await?;
+            addr.push(address::DOMAIN_ADDR);
+            addr.push(host.len() as u8);
+            addr.extend_from_slice(&host);
+        } else {
+            addr.push(address::IPV4_ADDR);
+            addr.extend_from_slice(&ip);
+        }
+        addr.extend_from_slice(&port.to_be_bytes());
+
+        // socks4 carries no password, so it's refused when authentication is required.
+        if !self.local.users.is_empty() {
+            self.reply(&mut conn, REP_NOT_ALLOWED, &unspecified())
+                .await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "socks4 is refused since authentication is required",
+            ));
+        }
+
+        match cmd {
+            CONNECT => self.connect(conn, addr).await,
+            BIND => self.bind(conn, addr).await,
+            _ => {
+                self.reply(&mut conn, REP_COMMAND_NOT_SUPPORTED, &unspecified())
+                    .await?;
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unsupported cmd type",
+                ))
+            }
+        }
+    }
+
+    // returns a reply formed as follows:
+    //         +----+----+----+----+----+----+----+----+
+    //         | VN | CD | DSTPORT |      DSTIP        |
+    //         +----+----+----+----+----+----+----+----+
+    //            1    1      2              4
+    // VN is 0, CD is 90 if the request is granted, 91 otherwise.
+    pub(super) async fn reply_v4(
+        &self,
+        conn: &mut TcpStream,
+        rep: u8,
+        bnd_addr: &SocketAddr,
+    ) -> io::Result<()> {
+        let cd = if rep == REP_SUCCEEDED {
+            REP_V4_GRANTED
+        } else {
+            REP_V4_REJECTED
+        };
+        let mut reply = vec![0x00, cd];
+        reply.extend_from_slice(&bnd_addr.port().to_be_bytes());
+        match bnd_addr {
+            SocketAddr::V4(addr) => reply.extend_from_slice(&addr.ip().octets()),
+            SocketAddr::V6(_) => reply.extend_from_slice(&[0, 0, 0, 0]),
+        }
+        conn.write_all(&reply).await
+    }
+}
+
+// read_cstring reads a null terminated string.
+async fn read_cstring(conn: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut s = Vec::new();
+    loop {
+        let b = conn.read_u8().await?;
+        if b == 0 {
+            return Ok(s);
+        }
+        if s.len() == MAX_CSTRING_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "socks4 string too long",
+            ));
+        }
+        s.push(b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::new_relay;
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const NO_AUTH: &str = "{address: 127.0.0.1, port: 0}";
+
+    // echo starts a server echoing what it receives and returns its port.
+    async fn echo() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = conn.split();
+                    let _ = io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        port
+    }
+
+    // socks4 sends request to a relay of local, and returns the connection and the
+    // reply received, empty if the connection was closed without one.
+    async fn socks4(local: &str, request: &[u8]) -> (TcpStream, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let relay = new_relay(local);
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let _ = relay.serve_v4(conn).await;
+        });
+        client.write_all(request).await.unwrap();
+        let mut reply = vec![0u8; 8];
+        match client.read_exact(&mut reply).await {
+            Ok(_) => (client, reply),
+            Err(_) => (client, Vec::new()),
+        }
+    }
+
+    fn request(cd: u8, port: u16, ip: [u8; 4], userid: &[u8], host: Option<&str>) -> Vec<u8> {
+        let mut req = vec![SOCKS_V4, cd];
+        req.extend_from_slice(&port.to_be_bytes());
+        req.extend_from_slice(&ip);
+        req.extend_from_slice(userid);
+        req.push(0);
+        if let Some(host) = host {
+            req.extend_from_slice(host.as_bytes());
+            req.push(0);
+        }
+        req
+    }
+
+    async fn assert_echo(mut conn: TcpStream) {
+        conn.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let port = echo().await;
+        let (conn, reply) =
+            socks4(NO_AUTH, &request(CONNECT, port, [127, 0, 0, 1], b"", None)).await;
+        assert_eq!(reply[..2], [0x00, REP_V4_GRANTED]);
+        assert_eq!(reply[4..], [127, 0, 0, 1]);
+        assert_echo(conn).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_4a() {
+        let port = echo().await;
+        let req = request(CONNECT, port, [0, 0, 0, 1], b"alice", Some("localhost"));
+        let (conn, reply) = socks4(NO_AUTH, &req).await;
+        assert_eq!(reply[..2], [0x00, REP_V4_GRANTED]);
+        assert_echo(conn).await;
+    }
+
+    #[tokio::test]
+    async fn test_userid() {
+        let port = echo().await;
+        // the userid is ignored without users.
+        let req = request(CONNECT, port, [127, 0, 0, 1], b"anyone", None);
+        let (conn, reply) = socks4(NO_AUTH, &req).await;
+        assert_eq!(reply[..2], [0x00, REP_V4_GRANTED]);
+        assert_echo(conn).await;
+
+        // socks4 can't authenticate, even a known userid is rejected.
+        let auth = "{address: 127.0.0.1, port: 0, users: [{username: alice, password: secret}]}";
+        let req = request(CONNECT, port, [127, 0, 0, 1], b"alice", None);
+        let (_, reply) = socks4(auth, &req).await;
+        assert_eq!(reply, [0x00, REP_V4_REJECTED, 0, 0, 0, 0, 0, 0]);
+
+        let long = vec![b'a'; MAX_CSTRING_LEN + 1];
+        let req = request(CONNECT, port, [127, 0, 0, 1], &long, None);
+        let (_, reply) = socks4(NO_AUTH, &req).await;
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let closed_port = {
+            let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let req = request(CONNECT, closed_port, [127, 0, 0, 1], b"", None);
+        let (_, reply) = socks4(NO_AUTH, &req).await;
+        assert_eq!(reply[..2], [0x00, REP_V4_REJECTED]);
+
+        let req = request(0x09, echo().await, [127, 0, 0, 1], b"", None);
+        let (_, reply) = socks4(NO_AUTH, &req).await;
+        assert_eq!(reply[..2], [0x00, REP_V4_REJECTED]);
+    }
+}
@@ -0,0 +1,12 @@
+// Package transport carries streams between client and server over protocols
+// other proxies and web servers understand.
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub mod plugin;
+pub mod tls;
+pub mod websocket;
+
+// Stream is a connection transports are layered on.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
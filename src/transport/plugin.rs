@@ -0,0 +1,211 @@
+// Package plugin runs SIP003 plugins, external processes tunneling the stream
+// between a local port and the remote one.
+use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{signal as tokio_signal, time};
+
+// A plugin exiting is restarted after this delay.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// A plugin exiting within this time of its start is taken to have failed to listen.
+const STARTUP_TIME: Duration = Duration::from_secs(1);
+
+// free_port picks a free loopback port. It's free as the listener is dropped, and
+// another process may take it before the plugin listens on it.
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+// LocalAddr is the local address of a started plugin. Its port moves as the plugin
+// is restarted on a new one.
+#[derive(Clone)]
+pub struct LocalAddr {
+    ip: IpAddr,
+    port: Arc<AtomicU16>,
+}
+
+impl LocalAddr {
+    fn new(local: SocketAddr) -> LocalAddr {
+        LocalAddr {
+            ip: local.ip(),
+            port: Arc::new(AtomicU16::new(local.port())),
+        }
+    }
+
+    pub fn get(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port.load(Ordering::Relaxed))
+    }
+}
+
+// Plugin is a SIP003 plugin listening on local and tunneling to remote. Clients
+// connect to local, servers listen on local behind the plugin listening on remote.
+pub struct Plugin {
+    plugin: String,
+    opts: String,
+    remote_host: String,
+    remote_port: u16,
+    local: LocalAddr,
+    // listens_local is set if the plugin listens on local rather than connects to it.
+    listens_local: bool,
+}
+
+impl Plugin {
+    // Plugin::new picks a free loopback port for the plugin to listen on. The port may
+    // be taken by another process before the plugin binds it, so a plugin exiting
+    // right after its start is restarted on a new port.
+    pub fn new(plugin: &str, opts: &str, remote_host: &str, remote_port: u16) -> Result<Plugin> {
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), free_port()?);
+        Ok(Plugin {
+            listens_local: true,
+            ..Plugin::with_local(plugin, opts, remote_host, remote_port, local)
+        })
+    }
+
+    // Plugin::with_local makes the plugin connect to local, which the caller listens on.
+    pub fn with_local(
+        plugin: &str,
+        opts: &str,
+        remote_host: &str,
+        remote_port: u16,
+        local: SocketAddr,
+    ) -> Plugin {
+        Plugin {
+            plugin: plugin.to_string(),
+            opts: opts.to_string(),
+            remote_host: remote_host.to_string(),
+            remote_port,
+            local: LocalAddr::new(local),
+            listens_local: false,
+        }
+    }
+
+    fn spawn(&self) -> Result<Child> {
+        let local = self.local.get();
+        Command::new(&self.plugin)
+            .env("SS_REMOTE_HOST", &self.remote_host)
+            .env("SS_REMOTE_PORT", self.remote_port.to_string())
+            .env("SS_LOCAL_HOST", local.ip().to_string())
+            .env("SS_LOCAL_PORT", local.port().to_string())
+            .env("SS_PLUGIN_OPTIONS", &self.opts)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+    }
+
+    // start spawns the plugin and restarts it whenever it exits, and returns the local
+    // address. Failing to spawn it the first time is an error, later it's retried.
+    pub fn start(self) -> Result<LocalAddr> {
+        let local = self.local.clone();
+        let mut child = self.spawn()?;
+        let mut started = Instant::now();
+        info!("plugin {} started on {}", self.plugin, local.get());
+        tokio::spawn(async move {
+            loop {
+                match child.wait().await {
+                    Ok(status) => error!("plugin {} exited with {}", self.plugin, status),
+                    Err(e) => error!("wait plugin {} failed {}", self.plugin, e),
+                }
+                let move_port = self.listens_local && started.elapsed() < STARTUP_TIME;
+                loop {
+                    time::sleep(RESTART_DELAY).await;
+                    if move_port {
+                        match free_port() {
+                            Ok(port) => self.local.port.store(port, Ordering::Relaxed),
+                            Err(e) => error!("pick port for plugin {} failed {}", self.plugin, e),
+                        }
+                    }
+                    match self.spawn() {
+                        Ok(c) => {
+                            child = c;
+                            started = Instant::now();
+                            info!("plugin {} restarted on {}", self.plugin, self.local.get());
+                            break;
+                        }
+                        Err(e) => error!("restart plugin {} failed {}", self.plugin, e),
+                    }
+                }
+            }
+        });
+        Ok(local)
+    }
+}
+
+// shutdown waits for SIGINT or SIGTERM. Binaries return on it, so that plugins are
+// killed as their tasks are dropped instead of being left running.
+pub async fn shutdown() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = tokio_signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio_signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_plugin() {
+        let dir = std::env::temp_dir().join(format!("mika-plugin-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out");
+        let script = dir.join("plugin.sh");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$SS_REMOTE_HOST $SS_REMOTE_PORT $SS_LOCAL_HOST $SS_LOCAL_PORT $SS_PLUGIN_OPTIONS\" >> {}\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let plugin = Plugin::new(
+            script.to_str().unwrap(),
+            "mode=ws;host=a.com",
+            "1.2.3.4",
+            443,
+        );
+        let local = plugin.unwrap().start().unwrap();
+        let port = local.get().port();
+        // the plugin exits at once and is restarted on a new port.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let lines = loop {
+            let lines = fs::read_to_string(&out).unwrap_or_default();
+            if lines.lines().count() >= 2 {
+                break lines;
+            }
+            assert!(Instant::now() < deadline, "plugin not restarted");
+            time::sleep(Duration::from_millis(50)).await;
+        };
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!("1.2.3.4 443 127.0.0.1 {} mode=ws;host=a.com", port)
+        );
+        assert_ne!(lines[1].split(' ').nth(3), Some(port.to_string().as_str()));
+        assert_ne!(local.get().port(), port);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(Plugin::new("/nonexistent/plugin", "", "1.2.3.4", 443)
+            .unwrap()
+            .start()
+            .is_err());
+    }
+}
@@ -0,0 +1,271 @@
+// Package tls wraps the connection between client and server in TLS, so that the
+// server can run on 443 with an existing certificate.
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::sync::Arc;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::TlsConfig;
+
+fn invalid(e: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidInput, e.to_string())
+}
+
+// load_certs reads all certificates of the PEM file at path.
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path)));
+    }
+    Ok(certs)
+}
+
+// load_key reads the first private key of the PEM file at path.
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid(format!("no private key in {}", path)))
+}
+
+// fingerprint returns the SHA-256 digest of cert.
+fn fingerprint(cert: &[u8]) -> [u8; 32] {
+    let mut sha256 = Sha256::new();
+    sha256.input(cert);
+    let mut digest = [0u8; 32];
+    sha256.result(&mut digest);
+    digest
+}
+
+// parse_pin parses the hex digest of a certificate, bytes may be separated by colons.
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    hex::decode_to_slice(pin.replace(':', ""), &mut digest)
+        .map_err(|_| invalid(format!("invalid certificate pin {}", pin)))?;
+    Ok(digest)
+}
+
+// PinnedVerifier accepts the certificates whose digest is pinned, the handshake
+// signatures are still verified.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if self.pins.contains(&fingerprint(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate mismatches pins".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// connector builds the client side of cfg. Certificates are verified against the
+// pins if any, or else against the ca bundle or the webpki roots.
+pub fn connector(cfg: &TlsConfig) -> Result<TlsConnector> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+    let mut config = if !cfg.pins.is_empty() {
+        let pins = cfg
+            .pins
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<Vec<_>>>()?;
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier { pins, provider }))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        if cfg.ca.is_empty() {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        } else {
+            for cert in load_certs(&cfg.ca)? {
+                roots.add(cert).map_err(invalid)?;
+            }
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    config.alpn_protocols = cfg.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// connect makes the handshake of client over stream, the server name is host unless
+// it's set in cfg.
+pub async fn connect<S>(
+    connector: &TlsConnector,
+    stream: S,
+    cfg: &TlsConfig,
+    host: &str,
+) -> Result<client::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = if cfg.server_name.is_empty() {
+        host
+    } else {
+        &cfg.server_name
+    };
+    let name = ServerName::try_from(name.to_string()).map_err(invalid)?;
+    connector.connect(name, stream).await
+}
+
+// acceptor builds the server side of cfg from its certificate chain and key.
+pub fn acceptor(cfg: &TlsConfig) -> Result<TlsAcceptor> {
+    if cfg.cert.is_empty() || cfg.key.is_empty() {
+        return Err(invalid("tls cert and key are required to listen"));
+    }
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(&cfg.cert)?, load_key(&cfg.key)?)
+        .map_err(invalid)?;
+    config.alpn_protocols = cfg.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+    fn testdata(name: &str) -> String {
+        format!(
+            "{}/src/transport/testdata/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        )
+    }
+
+    fn server_config() -> TlsConfig {
+        TlsConfig {
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            cert: testdata("cert.pem"),
+            key: testdata("key.pem"),
+            ..Default::default()
+        }
+    }
+
+    // handshake connects a client of cfg to a server with the test certificate and
+    // returns the protocol negotiated.
+    async fn handshake(cfg: TlsConfig) -> Result<Option<Vec<u8>>> {
+        let (client, server) = io::duplex(1 << 16);
+        let acceptor = acceptor(&server_config()).unwrap();
+        tokio::spawn(async move {
+            if let Ok(mut stream) = acceptor.accept(server).await {
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        let mut stream = connect(&connector(&cfg)?, client, &cfg, "localhost").await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"ping");
+        Ok(stream.get_ref().1.alpn_protocol().map(|p| p.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_ca() {
+        let cfg = TlsConfig {
+            ca: testdata("ca.pem"),
+            alpn: vec!["http/1.1".to_string()],
+            ..Default::default()
+        };
+        let alpn = handshake(cfg.clone()).await.unwrap();
+        assert_eq!(alpn, Some(b"http/1.1".to_vec()));
+
+        // the name verified is the one of server_name.
+        let cfg = TlsConfig {
+            server_name: "example.com".to_string(),
+            ..cfg
+        };
+        assert!(handshake(cfg).await.is_err());
+        // the webpki roots don't trust the test certificate.
+        assert!(handshake(TlsConfig::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pins() {
+        let cert = &load_certs(&testdata("cert.pem")).unwrap()[0];
+        let pin = hex::encode(fingerprint(cert));
+        let cfg = TlsConfig {
+            pins: vec![pin],
+            ..Default::default()
+        };
+        assert_eq!(handshake(cfg).await.unwrap(), None);
+
+        let cfg = TlsConfig {
+            pins: vec![hex::encode([0u8; 32])],
+            ..Default::default()
+        };
+        assert!(handshake(cfg).await.is_err());
+        assert!(parse_pin("00:11").is_err());
+    }
+}
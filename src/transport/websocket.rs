@@ -0,0 +1,547 @@
+// Package websocket carries a stream in binary WebSocket (RFC 6455) frames, so that
+// the server can be put behind a reverse proxy.
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bstr::ByteSlice;
+use bytes::{Buf, BufMut, BytesMut};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use futures::ready;
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{self, Instant, Sleep};
+use tokio_util::io::poll_read_buf;
+
+use crate::config::WebSocketConfig;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// The head of a handshake is small, a longer one isn't from a peer.
+const MAX_HEAD_LEN: usize = 8192;
+// A write is sent in one frame carrying this many bytes at most.
+const MAX_FRAME_LEN: usize = 1 << 16;
+const MAX_CONTROL_LEN: usize = 125;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+
+// accept_key returns the Sec-WebSocket-Accept answering key.
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(WEBSOCKET_GUID);
+    let mut digest = [0u8; 20];
+    sha1.result(&mut digest);
+    base64::encode(digest)
+}
+
+// header returns the value of header name in the head of an HTTP message.
+pub(crate) fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        if k.trim().eq_ignore_ascii_case(name) {
+            Some(v.trim())
+        } else {
+            None
+        }
+    })
+}
+
+// has_token reports whether the comma separated header value has token.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// read_head reads into buf until the head of an HTTP message and returns its length.
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut) -> Result<usize> {
+    loop {
+        if let Some(idx) = buf.find("\r\n\r\n") {
+            return Ok(idx + 4);
+        }
+        if buf.len() >= MAX_HEAD_LEN {
+            return Err(invalid("websocket handshake too long"));
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+// connect sends the handshake of a client over stream and checks the response of server.
+// The Host header is host unless it's set in cfg.
+pub async fn connect<S>(
+    mut stream: S,
+    cfg: &WebSocketConfig,
+    host: &str,
+) -> Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut key = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = base64::encode(key);
+    let host = if cfg.host.is_empty() { host } else { &cfg.host };
+
+    let mut request = format!("GET {} HTTP/1.1\r\n", cfg.path);
+    request += &format!("Host: {}\r\n", host);
+    request += "Upgrade: websocket\r\n";
+    request += "Connection: Upgrade\r\n";
+    request += &format!("Sec-WebSocket-Key: {}\r\n", key);
+    request += "Sec-WebSocket-Version: 13\r\n";
+    for (name, value) in cfg.headers.iter() {
+        request += &format!("{}: {}\r\n", name, value);
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = BytesMut::with_capacity(1024);
+    let end = read_head(&mut stream, &mut buf).await?;
+    let head =
+        std::str::from_utf8(&buf[..end]).map_err(|_| invalid("invalid websocket response"))?;
+    let status = head.split(' ').nth(1).unwrap_or_default();
+    if status != "101" {
+        return Err(invalid(&format!(
+            "websocket handshake failed with status {}",
+            status
+        )));
+    }
+    if !has_token(header(head, "Upgrade"), "websocket") {
+        return Err(invalid("websocket response isn't an upgrade"));
+    }
+    if header(head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(invalid("websocket accept mismatches key"));
+    }
+
+    buf.advance(end);
+    Ok(WebSocketStream::new(stream, Role::Client, buf, cfg))
+}
+
+// accept reads the handshake of a client from stream and replies to it. Requests to
+// other paths or without upgrade are answered by an HTTP error, as a web server does.
+pub async fn accept<S>(mut stream: S, cfg: &WebSocketConfig) -> Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+    let end = read_head(&mut stream, &mut buf).await?;
+    let key = match check_request(&buf[..end], cfg) {
+        Ok(key) => key,
+        Err((status, e)) => {
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            stream.write_all(response.as_bytes()).await?;
+            return Err(e);
+        }
+    };
+
+    let mut response = "HTTP/1.1 101 Switching Protocols\r\n".to_string();
+    response += "Upgrade: websocket\r\n";
+    response += "Connection: Upgrade\r\n";
+    response += &format!("Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key));
+    stream.write_all(response.as_bytes()).await?;
+
+    buf.advance(end);
+    Ok(WebSocketStream::new(stream, Role::Server, buf, cfg))
+}
+
+// check_request checks the handshake of a client and returns its key, or the status
+// to answer with.
+fn check_request(
+    head: &[u8],
+    cfg: &WebSocketConfig,
+) -> std::result::Result<String, (&'static str, Error)> {
+    let bad_request = |msg| ("400 Bad Request", invalid(msg));
+    let head = std::str::from_utf8(head).map_err(|_| bad_request("invalid websocket request"))?;
+    let mut request_line = head.split("\r\n").next().unwrap_or_default().split(' ');
+    if request_line.next() != Some("GET") {
+        return Err(bad_request("invalid websocket request"));
+    }
+    let path = request_line.next().unwrap_or_default();
+    if path.split('?').next() != Some(cfg.path.as_str()) {
+        return Err(("404 Not Found", invalid("websocket path mismatches")));
+    }
+    if !cfg.host.is_empty()
+        && !header(head, "Host").is_some_and(|h| h.eq_ignore_ascii_case(&cfg.host))
+    {
+        return Err(("404 Not Found", invalid("websocket host mismatches")));
+    }
+    if !has_token(header(head, "Upgrade"), "websocket")
+        || !has_token(header(head, "Connection"), "upgrade")
+    {
+        return Err(bad_request("websocket request isn't an upgrade"));
+    }
+    if header(head, "Sec-WebSocket-Version") != Some("13") {
+        return Err(bad_request("unsupported websocket version"));
+    }
+    match header(head, "Sec-WebSocket-Key") {
+        Some(key) if base64::decode(key).map(|k| k.len()) == Ok(16) => Ok(key.to_string()),
+        _ => Err(bad_request("invalid websocket key")),
+    }
+}
+
+// Role decides masking: clients mask their frames, servers must not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server,
+}
+
+// FrameHeader is the header of a frame read.
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    len: u64,
+    header_len: usize,
+}
+
+// parse_frame_header parses the header starting buf, or returns None until it's read.
+fn parse_frame_header(buf: &[u8]) -> Option<FrameHeader> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut header_len) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(b), 10)
+        }
+        126 | 127 => return None,
+        n => (n as u64, 2),
+    };
+    let mask = if masked {
+        if buf.len() < header_len + 4 {
+            return None;
+        }
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&buf[header_len..header_len + 4]);
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    Some(FrameHeader {
+        fin: buf[0] & 0x80 != 0,
+        opcode: buf[0] & 0x0F,
+        mask,
+        len,
+        header_len,
+    })
+}
+
+// apply_mask masks or unmasks data, whose first byte is at offset pos of the payload.
+fn apply_mask(data: &mut [u8], mask: [u8; 4], pos: usize) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[(pos + i) % 4];
+    }
+}
+
+// WebSocketStream carries a stream in binary frames over stream. Pings are answered
+// and a close frame is sent on shutdown.
+pub struct WebSocketStream<S> {
+    stream: S,
+    role: Role,
+    // rbuf holds the frames read ahead.
+    rbuf: BytesMut,
+    // remaining is the payload left of current data frame, unmasked by mask.
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    mask_pos: usize,
+    read_closed: bool,
+    // wbuf holds whole frames not yet written.
+    wbuf: Vec<u8>,
+    wpos: usize,
+    close_sent: bool,
+    // ping_timer sends a ping every ping_interval to keep proxies from timing out.
+    ping_interval: Option<Duration>,
+    ping_timer: Pin<Box<Sleep>>,
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: S, role: Role, rbuf: BytesMut, cfg: &WebSocketConfig) -> WebSocketStream<S> {
+        let ping_interval = match cfg.ping_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let first_ping = Instant::now() + ping_interval.unwrap_or(Duration::from_secs(3600));
+        WebSocketStream {
+            stream,
+            role,
+            rbuf,
+            remaining: 0,
+            mask: None,
+            mask_pos: 0,
+            read_closed: false,
+            wbuf: Vec::new(),
+            wpos: 0,
+            close_sent: false,
+            ping_interval,
+            ping_timer: Box::pin(time::sleep_until(first_ping)),
+        }
+    }
+
+    // put_frame appends payload framed as one frame to wbuf.
+    fn put_frame(&mut self, opcode: u8, payload: &[u8]) {
+        self.wbuf.put_u8(0x80 | opcode);
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+        match payload.len() {
+            n if n < 126 => self.wbuf.put_u8(mask_bit | n as u8),
+            n if n <= 0xFFFF => {
+                self.wbuf.put_u8(mask_bit | 126);
+                self.wbuf.put_u16(n as u16);
+            }
+            n => {
+                self.wbuf.put_u8(mask_bit | 127);
+                self.wbuf.put_u64(n as u64);
+            }
+        }
+
+        let start = self.wbuf.len();
+        if self.role == Role::Client {
+            let mut mask = [0u8; 4];
+            rand::thread_rng().fill_bytes(&mut mask);
+            self.wbuf.put_slice(&mask);
+            self.wbuf.put_slice(payload);
+            apply_mask(&mut self.wbuf[start + 4..], mask, 0);
+        } else {
+            self.wbuf.put_slice(payload);
+        }
+    }
+
+    // poll_write_buf writes all frames in wbuf.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while self.wpos < self.wbuf.len() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.wbuf[self.wpos..]))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.wpos += n;
+        }
+        self.wbuf.clear();
+        self.wpos = 0;
+        Ok(()).into()
+    }
+
+    // poll_ping queues a ping whenever ping_interval elapses.
+    fn poll_ping(&mut self, cx: &mut Context<'_>) {
+        if let Some(interval) = self.ping_interval {
+            if self.ping_timer.as_mut().poll(cx).is_ready() && !self.close_sent {
+                self.put_frame(OP_PING, &[]);
+                self.ping_timer.as_mut().reset(Instant::now() + interval);
+                let _ = self.ping_timer.as_mut().poll(cx);
+            }
+        }
+    }
+
+    // handle_control handles a control frame read.
+    fn handle_control(&mut self, opcode: u8, payload: &[u8]) {
+        match opcode {
+            OP_PING if !self.close_sent => self.put_frame(OP_PONG, payload),
+            // the close frame answering is sent once the stream is shut down in turn,
+            // so that a close frame half-closes the stream as a FIN does.
+            OP_CLOSE => self.read_closed = true,
+            _ => {}
+        }
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = Pin::into_inner(self);
+        loop {
+            this.poll_ping(cx);
+            // answers to control frames are written as the stream is read.
+            if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+                return Err(e).into();
+            }
+            if this.read_closed {
+                return Ok(()).into();
+            }
+
+            if this.remaining > 0 && !this.rbuf.is_empty() {
+                let len = usize::min(this.rbuf.len(), buf.remaining());
+                let len = u64::min(len as u64, this.remaining) as usize;
+                if let Some(mask) = this.mask {
+                    apply_mask(&mut this.rbuf[..len], mask, this.mask_pos);
+                    this.mask_pos += len;
+                }
+                buf.put_slice(&this.rbuf[..len]);
+                this.rbuf.advance(len);
+                this.remaining -= len as u64;
+                return Ok(()).into();
+            }
+
+            if this.remaining == 0 {
+                if let Some(header) = parse_frame_header(&this.rbuf) {
+                    if header.mask.is_some() != (this.role == Role::Server) {
+                        return Err(invalid("websocket frame masking mismatches role")).into();
+                    }
+                    match header.opcode {
+                        OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                            this.rbuf.advance(header.header_len);
+                            this.remaining = header.len;
+                            this.mask = header.mask;
+                            this.mask_pos = 0;
+                            continue;
+                        }
+                        OP_CLOSE | OP_PING | OP_PONG => {
+                            if !header.fin || header.len > MAX_CONTROL_LEN as u64 {
+                                return Err(invalid("invalid websocket control frame")).into();
+                            }
+                            let frame_len = header.header_len + header.len as usize;
+                            if this.rbuf.len() >= frame_len {
+                                let mut payload = [0u8; MAX_CONTROL_LEN];
+                                let payload = &mut payload[..header.len as usize];
+                                payload.copy_from_slice(&this.rbuf[header.header_len..frame_len]);
+                                if let Some(mask) = header.mask {
+                                    apply_mask(payload, mask, 0);
+                                }
+                                this.rbuf.advance(frame_len);
+                                this.handle_control(header.opcode, payload);
+                                continue;
+                            }
+                        }
+                        _ => return Err(invalid("unknown websocket opcode")).into(),
+                    }
+                }
+            }
+
+            this.rbuf.reserve(MAX_FRAME_LEN);
+            let n = ready!(poll_read_buf(
+                Pin::new(&mut this.stream),
+                cx,
+                &mut this.rbuf
+            ))?;
+            if n == 0 {
+                // a peer closing without close frame ends the stream between frames.
+                if this.remaining == 0 && this.rbuf.is_empty() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = Pin::into_inner(self);
+        ready!(this.poll_write_buf(cx))?;
+        if this.close_sent {
+            return Err(ErrorKind::BrokenPipe.into()).into();
+        }
+
+        let n = usize::min(buf.len(), MAX_FRAME_LEN);
+        this.put_frame(OP_BINARY, &buf[..n]);
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Err(e).into();
+        }
+        Ok(n).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = Pin::into_inner(self);
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = Pin::into_inner(self);
+        if !this.close_sent {
+            this.put_frame(OP_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+            this.close_sent = true;
+        }
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io;
+
+    #[test]
+    fn test_accept_key() {
+        // the example of RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    fn test_config() -> WebSocketConfig {
+        WebSocketConfig {
+            path: "/ws".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        let (client, server) = io::duplex(1 << 16);
+        let cfg = test_config();
+        let server = tokio::spawn(async move {
+            let mut ws = accept(server, &test_config()).await.unwrap();
+            let mut buf = Vec::new();
+            ws.read_to_end(&mut buf).await.unwrap();
+            ws.write_all(&buf).await.unwrap();
+            ws.shutdown().await.unwrap();
+        });
+
+        let mut ws = connect(client, &cfg, "example.com").await.unwrap();
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        ws.write_all(&data).await.unwrap();
+        // a ping is answered while the stream is read.
+        ws.put_frame(OP_PING, b"ping");
+        ws.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        ws.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_rejected() {
+        let (client, server) = io::duplex(4096);
+        tokio::spawn(async move {
+            let _ = accept(server, &test_config()).await;
+        });
+        let cfg = WebSocketConfig {
+            path: "/other".to_string(),
+            ..Default::default()
+        };
+        let err = connect(client, &cfg, "example.com").await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}